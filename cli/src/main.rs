@@ -1,6 +1,8 @@
 use clap::{Parser, Subcommand};
-use core::{ApplyPatchOptions, MakePatchOptions};
-use std::path::PathBuf;
+use core::codec::{self, Codec};
+use core::{ApplyPatchOptions, CompressionMode, IncompressibleCheck, MakePatchOptions, PatchLimits, PatchOp};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(name = "patchforge")]
@@ -22,13 +24,64 @@ enum Commands {
         #[arg(value_name = "DST")]
         dst: PathBuf,
 
-        /// Output patch file
+        /// Output patch file (`-` for stdout)
         #[arg(value_name = "PATCH")]
         patch: PathBuf,
 
-        /// Zstd compression level (0-22, -1 for no compression)
-        #[arg(short, long, default_value = "3")]
-        zstd_level: i32,
+        /// Compression level (zstd 0-22, xz 0-9, brotli 0-11; -1 for no compression)
+        #[arg(short = 'z', long, visible_alias = "zstd-level", default_value = "3")]
+        level: i32,
+
+        /// Codec for ADD data: zstd, lz4, xz, brotli or store
+        #[arg(long, default_value = "zstd", value_parser = parse_codec)]
+        codec: &'static dyn Codec,
+
+        /// Codec for files with an extension, as EXT=CODEC (repeatable)
+        #[arg(long, value_name = "EXT=CODEC", value_parser = parse_codec_override)]
+        codec_for: Vec<(String, &'static dyn Codec)>,
+
+        /// How ADD data is compressed: blocks, solid or seekable
+        #[arg(long, default_value = "blocks")]
+        compression_mode: CompressionMode,
+
+        /// Compress changed data against the old version of each file
+        #[arg(long)]
+        patch_from: bool,
+
+        /// Train a zstd dictionary of this many bytes on the new data (0 = off)
+        #[arg(long, default_value_t = 0)]
+        dictionary_size: usize,
+
+        /// Compress ADD data even when it does not shrink
+        #[arg(long)]
+        no_skip_incompressible: bool,
+
+        /// Store ADD data raw when compression saves less than this fraction
+        #[arg(long, default_value_t = 0.02)]
+        min_compression_gain: f64,
+
+        /// Worker threads for hashing and compression (0 = all cores)
+        #[arg(short = 'j', long, default_value_t = 0)]
+        threads: usize,
+
+        /// Store repeated new blocks each time instead of once (with --no-output-copies)
+        #[arg(long)]
+        no_dedupe: bool,
+
+        /// Read repeated new blocks from the patch instead of copying them
+        /// from the files already rebuilt
+        #[arg(long)]
+        no_output_copies: bool,
+
+        /// Store new blocks in full instead of as XOR deltas against
+        /// similar source blocks
+        #[arg(long)]
+        no_similar_blocks: bool,
+
+        /// Diff each file against the source file at the same path only,
+        /// instead of pairing renamed files with their most similar one
+        #[arg(long)]
+        no_rename_detection: bool,
     },
 
     /// Apply a patch file
@@ -44,6 +97,36 @@ enum Commands {
         /// Skip checksum verification
         #[arg(short, long)]
         no_verify: bool,
+
+        /// Files rebuilt concurrently (0 = all cores)
+        #[arg(short = 'j', long, default_value_t = 0)]
+        threads: usize,
+
+        /// Bytes of output written concurrently across files
+        #[arg(long, default_value_t = 1 << 30)]
+        io_budget: u64,
+
+        /// Copy unchanged data through userspace instead of cloning it
+        #[arg(long)]
+        no_fast_copy: bool,
+    },
+
+    /// Print the manifest of a patch file
+    Manifest {
+        /// Patch file
+        #[arg(value_name = "PATCH")]
+        patch: PathBuf,
+
+        /// Export the full manifest as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Summarize a patch file: sizes, ops and renamed files
+    Info {
+        /// Patch file
+        #[arg(value_name = "PATCH")]
+        patch: PathBuf,
     },
 }
 
@@ -55,39 +138,217 @@ fn main() -> core::Result<()> {
             src,
             dst,
             patch,
-            zstd_level,
+            level,
+            codec,
+            codec_for,
+            compression_mode,
+            patch_from,
+            dictionary_size,
+            no_skip_incompressible,
+            min_compression_gain,
+            threads,
+            no_dedupe,
+            no_output_copies,
+            no_similar_blocks,
+            no_rename_detection,
         } => {
-            println!("Creating patch: {} -> {}", src.display(), dst.display());
-            println!("Output: {}", patch.display());
+            // Keep stdout clean when the patch itself is written there
+            let to_stdout = patch == Path::new("-");
+            if !to_stdout {
+                println!("Creating patch: {} -> {}", src.display(), dst.display());
+                println!("Output: {}", patch.display());
+            }
 
             let opts = MakePatchOptions {
                 block_size: 4096,
-                zstd_level,
+                zstd_level: level,
                 verify_checksums: true,
+                compression_mode,
+                patch_from,
+                dictionary_size,
+                codec,
+                codec_by_extension: codec_for,
+                skip_incompressible: (!no_skip_incompressible).then(|| IncompressibleCheck {
+                    min_gain: min_compression_gain,
+                    ..Default::default()
+                }),
+                threads,
+                dedupe: !no_dedupe,
+                output_copies: !no_output_copies,
+                similar_blocks: !no_similar_blocks,
+                detect_renames: !no_rename_detection,
+                ..Default::default()
             };
 
             core::make_patch(&src, &dst, &patch, &opts)?;
-            println!("✓ Patch created successfully!");
+            if !to_stdout {
+                println!("✓ Patch created successfully!");
+            }
         }
 
         Commands::Apply {
             target,
             patch,
             no_verify,
+            threads,
+            io_budget,
+            no_fast_copy,
         } => {
             println!("Applying patch: {}", patch.display());
             println!("Target: {}", target.display());
 
             let opts = ApplyPatchOptions {
                 verify_checksums: !no_verify,
-                atomic: true,
+                threads,
+                max_inflight_bytes: io_budget,
+                fast_copy: !no_fast_copy,
+                ..Default::default()
             };
 
             core::apply_patch(&target, &patch, &opts)?;
             println!("✓ Patch applied successfully!");
         }
+
+        Commands::Manifest { patch, json } => {
+            let entries = core::manifest_entries(&patch, &PatchLimits::default())?;
+            if json {
+                let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+                entries.write_json(&mut out)?;
+                writeln!(out)?;
+                return Ok(());
+            }
+
+            for entry in entries {
+                let entry = entry?;
+                if entry.entry_type == "file" {
+                    let mut counts = [0; OP_KINDS.len()];
+                    count_ops(&mut counts, &entry.ops);
+                    println!("file  {}  ({})", entry.path, format_counts(&counts));
+                } else {
+                    println!("{:<4}  {}", entry.entry_type, entry.path);
+                }
+            }
+        }
+
+        Commands::Info { patch } => print_info(&patch, &mut std::io::stdout().lock())?,
     }
 
     Ok(())
 }
 
+/// Summarize a patch file: sizes, ops and renamed files.
+fn print_info(patch: &Path, out: &mut impl Write) -> core::Result<()> {
+    let entries = core::manifest_entries(patch, &PatchLimits::default())?;
+    let header = entries.header();
+    writeln!(out, "Patch: {} ({} bytes)", patch.display(), std::fs::metadata(patch)?.len())?;
+    writeln!(
+        out,
+        "Manifest version {}, {} compression{}",
+        header.version,
+        format!("{:?}", header.compression_mode).to_lowercase(),
+        if header.dictionary_offset.is_some() { ", with dictionary" } else { "" }
+    )?;
+
+    let (mut files, mut dirs, mut output) = (0, 0, 0u64);
+    let mut counts = [0; OP_KINDS.len()];
+    let mut renames = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.entry_type != "file" {
+            dirs += 1;
+            continue;
+        }
+        files += 1;
+        count_ops(&mut counts, &entry.ops);
+        output += entry.ops.iter().map(op_len).sum::<u64>();
+        if let Some(from) = entry.renamed_from {
+            renames.push((from, entry.path));
+        }
+    }
+
+    writeln!(out, "Files: {}, directories: {}", files, dirs)?;
+    writeln!(out, "Output: {} bytes", output)?;
+    writeln!(out, "Ops: {}", format_counts(&counts))?;
+    if !renames.is_empty() {
+        writeln!(out, "Renamed ({}):", renames.len())?;
+        for (from, to) in renames {
+            writeln!(out, "  {} -> {}", from, to)?;
+        }
+    }
+    Ok(())
+}
+
+/// Op kinds as listed; kinds after COPY and ADD are shown only when present.
+/// XOR counts the ADD ops that are deltas.
+const OP_KINDS: [&str; 6] = ["COPY", "ADD", "COPY_OUTPUT", "ZERO", "FILL", "XOR"];
+
+fn count_ops(counts: &mut [usize; OP_KINDS.len()], ops: &[PatchOp]) {
+    for op in ops {
+        let kind = match op {
+            PatchOp::Copy { .. } => 0,
+            PatchOp::Add { .. } => 1,
+            PatchOp::CopyOutput { .. } => 2,
+            PatchOp::Zero { .. } => 3,
+            PatchOp::Fill { .. } => 4,
+        };
+        counts[kind] += 1;
+        if matches!(op, PatchOp::Add { xor: Some(_), .. }) {
+            counts[5] += 1;
+        }
+    }
+}
+
+fn format_counts(counts: &[usize; OP_KINDS.len()]) -> String {
+    counts
+        .iter()
+        .zip(OP_KINDS)
+        .enumerate()
+        .filter(|&(i, (&n, _))| i < 2 || n > 0)
+        .map(|(_, (n, name))| format!("{} {}", n, name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Bytes an op produces.
+fn op_len(op: &PatchOp) -> u64 {
+    match op {
+        PatchOp::Copy { len, .. } => *len as u64,
+        PatchOp::Add { data_length, .. } => *data_length,
+        PatchOp::CopyOutput { len, .. } | PatchOp::Zero { len } | PatchOp::Fill { len, .. } => *len,
+    }
+}
+
+fn parse_codec(name: &str) -> Result<&'static dyn Codec, String> {
+    codec::by_name(name).map_err(|e| e.to_string())
+}
+
+fn parse_codec_override(arg: &str) -> Result<(String, &'static dyn Codec), String> {
+    let (ext, name) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected EXT=CODEC, got {:?}", arg))?;
+    Ok((ext.trim_start_matches('.').to_string(), parse_codec(name)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn info_lists_renamed_files() {
+        let (src, dst, out) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let data: Vec<u8> = (0..4 * 4096u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let mut edited = data.clone();
+        edited[100..200].fill(0);
+        std::fs::create_dir(src.path().join("old")).unwrap();
+        std::fs::create_dir(dst.path().join("new")).unwrap();
+        std::fs::write(src.path().join("old/report.txt"), &data).unwrap();
+        std::fs::write(dst.path().join("new/report.txt"), &edited).unwrap();
+        let patch = out.path().join("p.patch");
+        core::make_patch(src.path(), dst.path(), &patch, &MakePatchOptions::default()).unwrap();
+
+        let mut info = Vec::new();
+        print_info(&patch, &mut info).unwrap();
+        let info = String::from_utf8(info).unwrap();
+        assert!(info.contains("Renamed (1):\n  old/report.txt -> new/report.txt\n"), "{}", info);
+    }
+}
@@ -0,0 +1,229 @@
+//! Compression codecs for ADD blobs.
+//!
+//! Every codec has a stable one-byte id stored in the blob header and a
+//! name used in the manifest (`compression`) and on the command line. The
+//! registry maps between the two. Zstd's reference prefix and dictionary
+//! support lives in [`crate::compress`]; the other codecs are plain.
+
+use crate::types::{PatchError, Result};
+use std::io::{Read, Write};
+
+/// Id of the store (uncompressed) codec.
+pub const STORE_ID: u8 = 0;
+/// Id of the zstd codec.
+pub const ZSTD_ID: u8 = 1;
+/// Id of the lz4 frame codec.
+pub const LZ4_ID: u8 = 3;
+/// Id of the xz (lzma2) codec.
+pub const XZ_ID: u8 = 4;
+/// Id of the brotli codec.
+pub const BROTLI_ID: u8 = 5;
+
+/// A compression codec usable for ADD blobs.
+pub trait Codec: Send + Sync + std::fmt::Debug {
+    /// Id stored in the blob header.
+    fn id(&self) -> u8;
+
+    /// Name stored in the manifest's `compression` field.
+    fn name(&self) -> &'static str;
+
+    /// Compress `data`. `level` is clamped to the codec's own range.
+    fn compress(&self, data: &[u8], level: i32) -> Result<Vec<u8>>;
+
+    /// Decompress a whole stream, failing once the output exceeds `max_len`.
+    fn decompress(&self, input: &mut dyn Read, max_len: u64) -> Result<Vec<u8>>;
+}
+
+/// Stores data as is.
+#[derive(Debug)]
+pub struct Store;
+
+/// Zstandard, the default codec.
+#[derive(Debug)]
+pub struct Zstd;
+
+/// LZ4 frames: lower ratio, fastest apply.
+#[derive(Debug)]
+pub struct Lz4;
+
+/// xz / lzma2: best ratio, slowest.
+#[derive(Debug)]
+pub struct Xz;
+
+/// Brotli.
+#[derive(Debug)]
+pub struct Brotli;
+
+static REGISTRY: [&dyn Codec; 5] = [&Store, &Zstd, &Lz4, &Xz, &Brotli];
+
+/// Look up a codec by its blob header id.
+pub fn by_id(id: u8) -> Result<&'static dyn Codec> {
+    REGISTRY
+        .iter()
+        .copied()
+        .find(|c| c.id() == id)
+        .ok_or_else(|| PatchError::Format(format!("Unknown codec id {}", id)))
+}
+
+/// Look up a codec by name.
+pub fn by_name(name: &str) -> Result<&'static dyn Codec> {
+    REGISTRY
+        .iter()
+        .copied()
+        .find(|c| c.name() == name)
+        .ok_or_else(|| PatchError::Unsupported(format!("Unknown codec {:?}", name)))
+}
+
+/// Read `input` to the end, failing once it yields more than `max_len` bytes.
+pub fn read_bounded<R: Read>(input: R, max_len: u64, what: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    input
+        .take(max_len.saturating_add(1))
+        .read_to_end(&mut out)
+        .map_err(|e| PatchError::Compression(format!("{} decompression failed: {}", what, e)))?;
+
+    if out.len() as u64 > max_len {
+        return Err(PatchError::Format(format!(
+            "ADD blob decompresses beyond limit {}",
+            max_len
+        )));
+    }
+    Ok(out)
+}
+
+fn compress_error(what: &str, e: impl std::fmt::Display) -> PatchError {
+    PatchError::Compression(format!("{} compression failed: {}", what, e))
+}
+
+impl Codec for Store {
+    fn id(&self) -> u8 {
+        STORE_ID
+    }
+
+    fn name(&self) -> &'static str {
+        "store"
+    }
+
+    fn compress(&self, data: &[u8], _level: i32) -> Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+
+    fn decompress(&self, input: &mut dyn Read, max_len: u64) -> Result<Vec<u8>> {
+        read_bounded(input, max_len, "Store")
+    }
+}
+
+impl Codec for Zstd {
+    fn id(&self) -> u8 {
+        ZSTD_ID
+    }
+
+    fn name(&self) -> &'static str {
+        "zstd"
+    }
+
+    fn compress(&self, data: &[u8], level: i32) -> Result<Vec<u8>> {
+        crate::compress::compress(data, level.max(0))
+    }
+
+    fn decompress(&self, input: &mut dyn Read, max_len: u64) -> Result<Vec<u8>> {
+        let decoder = zstd::stream::read::Decoder::new(input)
+            .map_err(|e| PatchError::Compression(format!("Zstd decompression failed: {}", e)))?;
+        read_bounded(decoder, max_len, "Zstd")
+    }
+}
+
+impl Codec for Lz4 {
+    fn id(&self) -> u8 {
+        LZ4_ID
+    }
+
+    fn name(&self) -> &'static str {
+        "lz4"
+    }
+
+    fn compress(&self, data: &[u8], _level: i32) -> Result<Vec<u8>> {
+        let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        encoder.write_all(data).map_err(|e| compress_error("Lz4", e))?;
+        encoder.finish().map_err(|e| compress_error("Lz4", e))
+    }
+
+    fn decompress(&self, input: &mut dyn Read, max_len: u64) -> Result<Vec<u8>> {
+        read_bounded(lz4_flex::frame::FrameDecoder::new(input), max_len, "Lz4")
+    }
+}
+
+impl Codec for Xz {
+    fn id(&self) -> u8 {
+        XZ_ID
+    }
+
+    fn name(&self) -> &'static str {
+        "xz"
+    }
+
+    fn compress(&self, data: &[u8], level: i32) -> Result<Vec<u8>> {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), level.clamp(0, 9) as u32);
+        encoder.write_all(data).map_err(|e| compress_error("Xz", e))?;
+        encoder.finish().map_err(|e| compress_error("Xz", e))
+    }
+
+    fn decompress(&self, input: &mut dyn Read, max_len: u64) -> Result<Vec<u8>> {
+        read_bounded(xz2::read::XzDecoder::new(input), max_len, "Xz")
+    }
+}
+
+impl Codec for Brotli {
+    fn id(&self) -> u8 {
+        BROTLI_ID
+    }
+
+    fn name(&self) -> &'static str {
+        "brotli"
+    }
+
+    fn compress(&self, data: &[u8], level: i32) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut out, 64 << 10, level.clamp(0, 11) as u32, 22);
+            encoder.write_all(data).map_err(|e| compress_error("Brotli", e))?;
+            encoder.flush().map_err(|e| compress_error("Brotli", e))?;
+        }
+        Ok(out)
+    }
+
+    fn decompress(&self, input: &mut dyn Read, max_len: u64) -> Result<Vec<u8>> {
+        read_bounded(brotli::Decompressor::new(input, 64 << 10), max_len, "Brotli")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_codec_round_trips() {
+        let data = b"every codec round trips its own output ".repeat(100);
+        for codec in REGISTRY {
+            for level in [-5, 0, 3, 100] {
+                let packed = codec.compress(&data, level).unwrap();
+                let unpacked = codec.decompress(&mut &packed[..], data.len() as u64).unwrap();
+                assert!(unpacked == data, "{} at level {}", codec.name(), level);
+            }
+            assert_eq!(by_id(codec.id()).unwrap().name(), codec.name());
+            assert_eq!(by_name(codec.name()).unwrap().id(), codec.id());
+        }
+    }
+
+    #[test]
+    fn decompression_stops_at_max_len() {
+        let data = vec![0u8; 64 << 10];
+        for codec in REGISTRY {
+            let packed = codec.compress(&data, 3).unwrap();
+            let result = codec.decompress(&mut &packed[..], data.len() as u64 - 1);
+            assert!(matches!(result, Err(PatchError::Format(_))), "{}", codec.name());
+        }
+        assert!(matches!(by_id(2), Err(PatchError::Format(_))));
+        assert!(matches!(by_name("gzip"), Err(PatchError::Unsupported(_))));
+    }
+}
@@ -1,20 +1,132 @@
 //! Compression utilities.
 
 use crate::types::Result;
+use std::io::Write;
+
+/// Largest window log used for patch-from compression (1 GiB, valid on
+/// 32-bit targets too).
+pub const PATCH_FROM_MAX_WINDOW_LOG: u32 = 30;
 
 /// Compress data with zstd at given level.
 pub fn compress(data: &[u8], level: i32) -> Result<Vec<u8>> {
+    compress_with(data, level, None)
+}
+
+/// Compress data with zstd at given level, against `dictionary` if given.
+pub fn compress_with(data: &[u8], level: i32, dictionary: Option<&Dictionary>) -> Result<Vec<u8>> {
     if level < 0 {
         return Ok(data.to_vec());
     }
-    zstd::encode_all(data, level).map_err(|e| {
+    let result = match dictionary {
+        Some(dict) => zstd::bulk::Compressor::with_prepared_dictionary(&dict.encoder)
+            .and_then(|mut compressor| compressor.compress(data)),
+        None => zstd::encode_all(data, level),
+    };
+    result.map_err(|e| {
         crate::types::PatchError::Compression(format!("Compression failed: {}", e))
     })
 }
 
+/// A trained zstd dictionary, prepared for compression at one level.
+pub struct Dictionary {
+    pub raw: Vec<u8>,
+    encoder: zstd::dict::EncoderDictionary<'static>,
+}
+
+impl Dictionary {
+    pub fn new(raw: Vec<u8>, level: i32) -> Self {
+        let encoder = zstd::dict::EncoderDictionary::copy(&raw, level);
+        Self { raw, encoder }
+    }
+
+    /// Train a dictionary of at most `max_size` bytes from `samples`.
+    /// Returns `None` when zstd cannot build one (e.g. too few samples).
+    pub fn train(samples: &[Vec<u8>], max_size: usize, level: i32) -> Option<Self> {
+        zstd::dict::from_samples(samples, max_size)
+            .ok()
+            .map(|raw| Self::new(raw, level))
+    }
+}
+
 /// Decompress data with zstd.
 pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
     zstd::decode_all(data).map_err(|e| {
         crate::types::PatchError::Compression(format!("Decompression failed: {}", e))
     })
 }
+
+/// Shannon entropy of the byte distribution of `data`, in bits per byte.
+/// Large inputs are estimated from up to 64 evenly spaced 4 KiB chunks.
+pub fn byte_entropy(data: &[u8]) -> f64 {
+    let chunks = data.len().div_ceil(4096);
+    let mut counts = [0u64; 256];
+    let mut total = 0u64;
+    for chunk in data.chunks(4096).step_by((chunks / 64).max(1)) {
+        for &b in chunk {
+            counts[b as usize] += 1;
+        }
+        total += chunk.len() as u64;
+    }
+    if total == 0 {
+        return 0.0;
+    }
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Compress data as independent frames of at most `frame_size` raw bytes,
+/// each produced by `compress_frame`, prefixed by a frame index:
+/// `frame_count: u32`, then `(compressed_len: u32, raw_len: u32)` per frame,
+/// all big-endian, followed by the frames themselves.
+pub fn compress_seekable(
+    data: &[u8],
+    frame_size: usize,
+    compress_frame: impl FnMut(&[u8]) -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let frame_size = frame_size.clamp(1, u32::MAX as usize);
+    let frames = data
+        .chunks(frame_size)
+        .map(compress_frame)
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&(frames.len() as u32).to_be_bytes());
+    for (frame, chunk) in frames.iter().zip(data.chunks(frame_size)) {
+        out.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        out.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+    }
+    for frame in &frames {
+        out.extend_from_slice(frame);
+    }
+    Ok(out)
+}
+
+/// Compress `data` with `prefix` (usually the old version of the same file)
+/// as a zstd reference prefix, like `zstd --patch-from`. The window is sized
+/// to reach back over the whole prefix and long-distance matching is on.
+pub fn compress_with_prefix(data: &[u8], level: i32, prefix: &[u8]) -> Result<Vec<u8>> {
+    let map_err = |e: std::io::Error| {
+        crate::types::PatchError::Compression(format!("Compression failed: {}", e))
+    };
+
+    let mut encoder =
+        zstd::stream::write::Encoder::with_ref_prefix(Vec::new(), level.max(1), prefix).map_err(map_err)?;
+    let window_log = patch_from_window_log((prefix.len() + data.len()) as u64);
+    encoder.window_log(window_log).map_err(map_err)?;
+    // Without long-distance matching the fast levels barely index the prefix
+    encoder.long_distance_matching(true).map_err(map_err)?;
+    encoder.write_all(data).map_err(map_err)?;
+    encoder.finish().map_err(map_err)
+}
+
+/// Window log covering `total_len` bytes of prefix plus data.
+pub fn patch_from_window_log(total_len: u64) -> u32 {
+    let bits = 64 - total_len.saturating_sub(1).leading_zeros();
+    bits.clamp(10, PATCH_FROM_MAX_WINDOW_LOG)
+}
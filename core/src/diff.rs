@@ -1,36 +1,63 @@
 //! Diff engine: folder walking, block hashing, operation generation.
+//!
+//! Files are hashed in parallel on the current rayon pool; results are
+//! always combined in sorted path order so the manifest is deterministic.
+//! Matching blocks that continue each other become a single ranged COPY.
+//! New blocks repeating an earlier new block become COPY_OUTPUT ops,
+//! all-zero blocks ZERO ops and blocks repeating a short pattern FILL ops;
+//! holes in sparse files are never read. A new block resembling a source
+//! block, found through a min-hash [`Sketch`], is stored as its XOR with it.
+//! Each destination file is paired with the source file it most likely came
+//! from, renamed or not, and matches are looked for there first.
 
+use crate::paths::{decode_relative_path, encode_path};
+use crate::sparse::Holes;
 use crate::types::*;
-use sha2::{Digest, Sha256};
+use rayon::prelude::*;
+use crate::verify::sha256_hex;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 
-/// Hash all blocks in a file.
-pub fn hash_file_blocks(file_path: &Path, block_size: usize) -> Result<Vec<BlockHash>> {
+/// Hash all blocks in a file, sketching full blocks too with `sketches`.
+/// Whole blocks inside holes are not read.
+pub fn hash_file_blocks(file_path: &Path, block_size: usize, sketches: bool) -> Result<Vec<BlockHash>> {
     let mut file = File::open(file_path)?;
     let mut blocks = Vec::new();
     let mut buffer = vec![0u8; block_size];
     let mut block_index = 0u64;
+    let mut holes = Holes::default();
+    let zero_hash = sha256_hex(&buffer);
 
     loop {
-        let n = file.read(&mut buffer)?;
+        let hole_blocks = holes.at(&file, block_index * block_size as u64) / block_size as u64;
+        if hole_blocks > 0 {
+            for _ in 0..hole_blocks {
+                blocks.push(BlockHash {
+                    sha256: zero_hash.clone(),
+                    file_path: file_path.to_path_buf(),
+                    block_index,
+                    len: block_size,
+                    sketch: None,
+                });
+                block_index += 1;
+            }
+            file.seek(SeekFrom::Start(block_index * block_size as u64))?;
+        }
+
+        let n = read_full(&mut file, &mut buffer)?;
         if n == 0 {
             break;
         }
 
-        let chunk = &buffer[..n];
-        let mut hasher = Sha256::new();
-        hasher.update(chunk);
-        let sha256_hex = format!("{:x}", hasher.finalize());
-
         blocks.push(BlockHash {
-            sha256: sha256_hex,
+            sha256: sha256_hex(&buffer[..n]),
             file_path: file_path.to_path_buf(),
             block_index,
             len: n,
+            sketch: if sketches && n == block_size { sketch(&buffer) } else { None },
         });
 
         block_index += 1;
@@ -39,117 +66,481 @@ pub fn hash_file_blocks(file_path: &Path, block_size: usize) -> Result<Vec<Block
     Ok(blocks)
 }
 
-/// Walk a directory and collect all file blocks with their paths.
-pub fn scan_tree(root: &Path, block_size: usize) -> Result<HashMap<String, Vec<BlockHash>>> {
-    let mut result = HashMap::new();
+/// Read until `buf` is full or the reader is exhausted, so block
+/// boundaries never depend on short reads. Returns the bytes read.
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
 
-    let mut entries: Vec<_> = WalkDir::new(root)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .collect();
+/// SHA-256 of each `block_size` block of `data`, hashed in parallel.
+pub fn hash_blocks(data: &[u8], block_size: usize) -> Vec<String> {
+    data.par_chunks(block_size).map(sha256_hex).collect()
+}
 
-    entries.sort_by(|a, b| a.path().cmp(b.path()));
+/// Source blocks by SHA-256, each mapped to its first occurrence in path
+/// order so matches are deterministic.
+pub struct SourceIndex {
+    blocks: HashMap<String, (usize, u64, usize)>, // sha256 -> (path index, block index, len)
+    root: PathBuf,
+    block_size: usize,
+    paths: Vec<String>,                   // Source files, in path order
+    sketches: HashMap<u64, (usize, u64)>, // Sketch value -> (path index, block index)
+    files: Vec<FileBlocks>,               // The blocks of each source file
+}
 
-    for entry in entries {
-        let full_path = entry.path();
-        let rel_path = full_path
-            .strip_prefix(root)
-            .map_err(|e| PatchError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+/// The blocks of one source file, each mapped to its first occurrence.
+struct FileBlocks {
+    hashes: HashMap<String, (u64, usize)>, // sha256 -> (block index, len)
+    sketches: HashMap<u64, u64>,          // Sketch value -> block index
+}
 
-        let rel_key = rel_path.to_string_lossy().to_string();
-        let blocks = hash_file_blocks(full_path, block_size)?;
-        result.insert(rel_key, blocks);
+impl SourceIndex {
+    /// Scan and index every block under `src_root`. With `similar`, full
+    /// blocks are also indexed by sketch for [`SourceIndex::similar`].
+    pub fn build(src_root: &Path, block_size: usize, similar: bool) -> Result<Self> {
+        let mut src_blocks = scan_tree(src_root, block_size, similar)?;
+        let mut src_paths: Vec<String> = src_blocks.keys().cloned().collect();
+        src_paths.sort();
+
+        let mut blocks = HashMap::new();
+        let mut sketches = HashMap::new();
+        let mut files = Vec::with_capacity(src_paths.len());
+        for (path_idx, file_path) in src_paths.iter().enumerate() {
+            let mut file = FileBlocks {
+                hashes: HashMap::new(),
+                sketches: HashMap::new(),
+            };
+            for block in src_blocks.remove(file_path).unwrap_or_default() {
+                for &value in block.sketch.iter().flatten() {
+                    sketches.entry(value).or_insert((path_idx, block.block_index));
+                    file.sketches.entry(value).or_insert(block.block_index);
+                }
+                blocks
+                    .entry(block.sha256.clone())
+                    .or_insert((path_idx, block.block_index, block.len));
+                file.hashes.entry(block.sha256).or_insert((block.block_index, block.len));
+            }
+            files.push(file);
+        }
+        Ok(Self {
+            blocks,
+            root: src_root.to_path_buf(),
+            block_size,
+            paths: src_paths,
+            sketches,
+            files,
+        })
     }
 
-    Ok(result)
-}
+    /// COPY op for a destination block with this hash, if the source has it.
+    pub fn copy_op(&self, sha256: &str) -> Option<PatchOp> {
+        self.blocks.get(sha256).map(|&(path_idx, block_index, len)| PatchOp::Copy {
+            src: self.paths[path_idx].clone(),
+            block_index,
+            len,
+        })
+    }
 
-/// Generate a manifest for transforming src_tree into dst_tree.
-pub fn generate_manifest(
-    src_root: &Path,
-    dst_root: &Path,
-    block_size: usize,
-) -> Result<Manifest> {
-    // Scan both trees
-    let src_blocks = scan_tree(src_root, block_size)?;
-    let dst_files = list_files_sorted(dst_root)?;
+    /// Like [`SourceIndex::copy_op`], but copying from `pair` when it holds
+    /// the block too.
+    pub fn paired_copy_op(&self, sha256: &str, pair: Option<&Pair>) -> Option<PatchOp> {
+        let in_pair = pair.and_then(|pair| {
+            let &(block_index, len) = self.files[pair.path_idx].hashes.get(sha256)?;
+            Some(PatchOp::Copy {
+                src: pair.src.clone(),
+                block_index,
+                len,
+            })
+        });
+        in_pair.or_else(|| self.copy_op(sha256))
+    }
+
+    /// The block of `pair` most similar to `sketch`, as in [`closest`], as
+    /// (path index, block index).
+    fn in_pair(&self, sketch: &Sketch, pair: &Pair) -> Option<(usize, u64)> {
+        closest(&self.files[pair.path_idx].sketches, sketch).map(|block_index| (pair.path_idx, block_index))
+    }
 
-    // Build a map: sha256 -> (file_path, block_index, len)
-    let mut src_block_map: HashMap<String, Vec<(String, u64, usize)>> = HashMap::new();
-    for (file_path, blocks) in &src_blocks {
-        for block in blocks {
-            src_block_map
-                .entry(block.sha256.clone())
-                .or_insert_with(Vec::new)
-                .push((file_path.clone(), block.block_index, block.len));
+    /// A similar source block for `block`, in `pair` if it has one, and
+    /// their XOR, if few enough bytes differ for the delta to pay off.
+    /// Source files are read through `open`.
+    pub fn xor_delta(
+        &self,
+        block: &[u8],
+        pair: Option<&Pair>,
+        open: &mut OpenSources,
+    ) -> Result<Option<(XorSource, Vec<u8>)>> {
+        let Some(sketch) = sketch(block) else {
+            return Ok(None);
+        };
+        let in_pair = pair.and_then(|pair| self.in_pair(&sketch, pair));
+        let candidates = in_pair.into_iter().chain(closest(&self.sketches, &sketch));
+        for (path_idx, block_index) in candidates {
+            let file = open.get(self, path_idx)?;
+            file.seek(SeekFrom::Start(block_index * self.block_size as u64))?;
+            let mut delta = vec![0u8; block.len()];
+            if read_full(file, &mut delta)? < block.len() {
+                continue;
+            }
+            for (d, b) in delta.iter_mut().zip(block) {
+                *d ^= b;
+            }
+            // Shifted data resembles its source but XORs to noise
+            let changed = delta.iter().filter(|&&b| b != 0).count();
+            if changed <= block.len() / MAX_XOR_CHANGED {
+                let source = XorSource {
+                    src: self.paths[path_idx].clone(),
+                    block_index,
+                };
+                return Ok(Some((source, delta)));
+            }
         }
+        Ok(None)
     }
 
-    let mut manifest = Manifest::new();
+    /// Pair the destination file `dst_path` with the source file it most
+    /// likely came from: among those holding at least a quarter of its
+    /// sampled blocks (see [`sample_blocks`]), exactly or by sketch, the
+    /// best by share plus name similarity. Failing that, the source file at
+    /// the same path, if any.
+    pub fn pair(&self, dst_path: &str, samples: &[(String, Option<Sketch>)]) -> Option<Pair> {
+        let mut shared: HashMap<usize, usize> = HashMap::new();
+        for (sha256, sketch) in samples {
+            let found = match self.blocks.get(sha256) {
+                Some(&(path_idx, ..)) => Some(path_idx),
+                None => sketch.as_ref().and_then(|s| closest(&self.sketches, s)).map(|(path_idx, _)| path_idx),
+            };
+            if let Some(path_idx) = found {
+                *shared.entry(path_idx).or_default() += 1;
+            }
+        }
 
-    // Process each file in destination
-    for dst_file_rel in dst_files {
-        let dst_full_path = dst_root.join(&dst_file_rel);
-        if !dst_full_path.is_file() {
+        let score = |path_idx: usize, n: usize| {
+            n as f64 / samples.len() as f64 + NAME_WEIGHT * name_similarity(dst_path, &self.paths[path_idx])
+        };
+        let best = shared
+            .into_iter()
+            .filter(|&(_, n)| n * 4 >= samples.len())
+            .map(|(path_idx, n)| (score(path_idx, n), path_idx))
+            // Equal scores go to the first path, whatever the map order
+            .max_by(|a, b| a.0.total_cmp(&b.0).then(b.1.cmp(&a.1)))
+            .map(|(_, path_idx)| path_idx);
+        let path_idx = best.or_else(|| self.paths.binary_search_by(|p| p.as_str().cmp(dst_path)).ok())?;
+        Some(Pair {
+            src: self.paths[path_idx].clone(),
+            path_idx,
+        })
+    }
+}
+
+/// Weight of file name similarity (0 to 1) next to the share of sampled
+/// blocks when pairing files.
+const NAME_WEIGHT: f64 = 0.25;
+
+/// Destination blocks sampled to pair a file.
+const PAIR_SAMPLES: u64 = 16;
+
+/// The source file a destination file is paired with. Its COPY and XOR ops
+/// look for blocks in this file first.
+pub struct Pair {
+    pub src: String,
+    path_idx: usize,
+}
+
+/// Hashes of up to [`PAIR_SAMPLES`] blocks spread over `file`, skipping
+/// zero and pattern blocks, for [`SourceIndex::pair`]; with `sketches`,
+/// full blocks are sketched too. The file is left at its start.
+pub fn sample_blocks(file: &mut File, block_size: usize, sketches: bool) -> Result<Vec<(String, Option<Sketch>)>> {
+    let blocks = file.metadata()?.len().div_ceil(block_size as u64);
+    let count = blocks.min(PAIR_SAMPLES);
+    let mut buffer = vec![0u8; block_size];
+    let mut samples = Vec::new();
+    for i in 0..count {
+        file.seek(SeekFrom::Start(i * blocks / count * block_size as u64))?;
+        let n = read_full(file, &mut buffer)?;
+        let block = &buffer[..n];
+        if block.is_empty() || is_zero(block) || fill_pattern(block).is_some() {
             continue;
         }
+        let sketch = if sketches && block.len() == block_size { sketch(block) } else { None };
+        samples.push((sha256_hex(block), sketch));
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(samples)
+}
 
-        let mut entry = ManifestEntry {
-            path: dst_file_rel.clone(),
-            entry_type: "file".to_string(),
-            mode: 0o644,
-            mtime: 0,
-            sha256: None,
-            ops: Vec::new(),
+/// Dice coefficient of the byte pairs of two paths' file names, 0 to 1.
+fn name_similarity(a: &str, b: &str) -> f64 {
+    let name = |p: &str| Path::new(p).file_name().and_then(|n| n.to_str()).unwrap_or(p).as_bytes().to_vec();
+    let (a, b) = (name(a), name(b));
+    if a.len() < 2 || b.len() < 2 {
+        return if a == b { 1.0 } else { 0.0 };
+    }
+    let mut unmatched: Vec<&[u8]> = b.windows(2).collect();
+    let mut common = 0;
+    for pair in a.windows(2) {
+        if let Some(i) = unmatched.iter().position(|&p| p == pair) {
+            unmatched.swap_remove(i);
+            common += 1;
+        }
+    }
+    2.0 * common as f64 / (a.len() + b.len() - 2) as f64
+}
+
+/// The value in `map` sharing the most of `sketch`'s values, if it shares
+/// at least half of them.
+fn closest<T: Copy + PartialEq>(map: &HashMap<u64, T>, sketch: &Sketch) -> Option<T> {
+    let mut best: Option<(T, usize)> = None;
+    for value in sketch {
+        let Some(&found) = map.get(value) else {
+            continue;
         };
+        let shared = sketch.iter().filter(|v| map.get(v) == Some(&found)).count();
+        if best.is_none_or(|(_, most)| shared > most) {
+            best = Some((found, shared));
+        }
+    }
+    best.filter(|&(_, shared)| shared * 2 >= SKETCH_LEN).map(|(found, _)| found)
+}
 
-        // Read and block-hash the destination file
-        let dst_blocks = hash_file_blocks(&dst_full_path, block_size)?;
-
-        for block_hash in dst_blocks {
-            if let Some(src_matches) = src_block_map.get(&block_hash.sha256) {
-                // Found a matching block in source
-                if let Some((src_file, src_idx, src_len)) = src_matches.first() {
-                    entry.ops.push(PatchOp::Copy {
-                        src: src_file.clone(),
-                        block_index: *src_idx,
-                        len: *src_len,
-                    });
-                    continue;
-                }
+/// Source files recently read for XOR deltas, so the paired file and the
+/// one read last are not reopened for every block.
+#[derive(Default)]
+pub struct OpenSources {
+    files: Vec<(usize, File)>, // (path index, handle), most recently used first
+}
+
+/// Source files [`OpenSources`] keeps open.
+const OPEN_SOURCES: usize = 2;
+
+impl OpenSources {
+    fn get(&mut self, index: &SourceIndex, path_idx: usize) -> Result<&mut File> {
+        match self.files.iter().position(|&(idx, _)| idx == path_idx) {
+            Some(at) => {
+                let file = self.files.remove(at);
+                self.files.insert(0, file);
             }
+            None => {
+                let path = index.root.join(decode_relative_path(&index.paths[path_idx])?);
+                let file = File::open(path)?;
+                self.files.truncate(OPEN_SOURCES - 1);
+                self.files.insert(0, (path_idx, file));
+            }
+        }
+        Ok(&mut self.files[0].1)
+    }
+}
 
-            // No match: we'll add this block later (placeholder for now)
-            entry.ops.push(PatchOp::Add {
-                data_offset: 0,      // Will be updated during write
-                data_length: block_hash.len as u64,
-                compressed: false,
-                compression: None,
-                zstd_level: None,
-            });
+/// An XOR delta is kept when at most 1 in this many bytes differ.
+const MAX_XOR_CHANGED: usize = 4;
+
+/// Bottom-k sketch of a block: the smallest hashes of its distinct 8-byte
+/// windows, or `None` if it has too few of them.
+pub fn sketch(block: &[u8]) -> Option<Sketch> {
+    let mut lowest = [u64::MAX; SKETCH_LEN];
+    for window in block.windows(8) {
+        let hash = mix64(u64::from_le_bytes(window.try_into().expect("8-byte window")));
+        if hash < lowest[SKETCH_LEN - 1] && !lowest.contains(&hash) {
+            let at = lowest.partition_point(|&v| v < hash);
+            lowest.copy_within(at..SKETCH_LEN - 1, at + 1);
+            lowest[at] = hash;
         }
+    }
+    (lowest[SKETCH_LEN - 1] != u64::MAX).then_some(lowest)
+}
+
+/// 64-bit finalizer of MurmurHash3.
+fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 33)).wrapping_mul(0xff51_afd7_ed55_8ccd);
+    x = (x ^ (x >> 33)).wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    x ^ (x >> 33)
+}
 
-        manifest.entries.push(entry);
+/// Whether a block XORed with `next` may extend an ADD of `len` bytes
+/// XORed with `prev`: both plain, or the source range continues.
+pub fn xor_continues(prev: Option<&XorSource>, len: u64, next: Option<&XorSource>, block_size: usize) -> bool {
+    match (prev, next) {
+        (None, None) => true,
+        (Some(prev), Some(next)) => {
+            prev.src == next.src
+                && len.is_multiple_of(block_size as u64)
+                && prev.block_index.checked_add(len / block_size as u64) == Some(next.block_index)
+        }
+        _ => false,
     }
+}
 
-    // Also add directories
-    for entry in list_dirs_sorted(dst_root)? {
-        manifest.entries.push(ManifestEntry {
-            path: entry,
-            entry_type: "dir".to_string(),
-            mode: 0o755,
-            mtime: 0,
-            sha256: None,
-            ops: Vec::new(),
-        });
+/// Append a COPY op to `ops`, extending the previous COPY instead when it
+/// ends on a block boundary right before this one in the same source file.
+pub fn push_copy(ops: &mut Vec<PatchOp>, op: PatchOp, block_size: usize) {
+    if let (
+        Some(PatchOp::Copy {
+            src: prev_src,
+            block_index: prev_index,
+            len: prev_len,
+        }),
+        PatchOp::Copy { src, block_index, len },
+    ) = (ops.last_mut(), &op)
+    {
+        let continues = *prev_src == *src
+            && prev_len.is_multiple_of(block_size)
+            && prev_index.checked_add((*prev_len / block_size) as u64) == Some(*block_index);
+        if let Some(merged) = prev_len.checked_add(*len).filter(|_| continues) {
+            *prev_len = merged;
+            return;
+        }
+    }
+    ops.push(op);
+}
+
+/// New destination blocks by SHA-256, each mapped to where its first
+/// occurrence is written, so repeats can be copied from the output.
+#[derive(Default)]
+pub struct OutputIndex {
+    blocks: HashMap<String, (usize, u64)>, // sha256 -> (entry index, offset in its file)
+}
+
+impl OutputIndex {
+    /// Record a new block written at `offset` in entry `entry_idx`, unless
+    /// the hash is already known.
+    pub fn insert(&mut self, sha256: String, entry_idx: usize, offset: u64) {
+        self.blocks.entry(sha256).or_insert((entry_idx, offset));
+    }
+
+    /// COPY_OUTPUT op for a repeated block of `len` bytes, if one with this
+    /// hash was written before. `path` gives the path of an entry index.
+    pub fn copy_op(&self, sha256: &str, len: usize, path: impl FnOnce(usize) -> String) -> Option<PatchOp> {
+        self.blocks.get(sha256).map(|&(entry_idx, offset)| PatchOp::CopyOutput {
+            path: path(entry_idx),
+            offset,
+            len: len as u64,
+        })
+    }
+}
+
+/// Whether a block is all zeros.
+pub fn is_zero(block: &[u8]) -> bool {
+    block.iter().all(|&b| b == 0)
+}
+
+/// The shortest pattern (at most [`MAX_FILL_PATTERN`] bytes) that `block`
+/// repeats at least twice, if any.
+pub fn fill_pattern(block: &[u8]) -> Option<&[u8]> {
+    (1..=MAX_FILL_PATTERN.min(block.len() / 2))
+        .find(|&period| block[period..] == block[..block.len() - period])
+        .map(|period| &block[..period])
+}
+
+/// Append a FILL op for a block repeating `pattern`, extending the previous
+/// FILL instead when the block continues its pattern.
+pub fn push_fill(ops: &mut Vec<PatchOp>, pattern: &[u8], len: u64) {
+    if let Some(PatchOp::Fill {
+        pattern: prev_pattern,
+        len: prev_len,
+    }) = ops.last_mut()
+    {
+        // Both patterns are the shortest period of their data, so the block
+        // continues the run exactly when its pattern is the rotated one
+        let phase = (*prev_len % prev_pattern.len() as u64) as usize;
+        let continues = prev_pattern.len() == pattern.len()
+            && prev_pattern[phase..] == pattern[..pattern.len() - phase]
+            && prev_pattern[..phase] == pattern[pattern.len() - phase..];
+        if continues {
+            *prev_len += len;
+            return;
+        }
+    }
+    ops.push(PatchOp::Fill {
+        pattern: pattern.to_vec(),
+        len,
+    });
+}
+
+/// Append a ZERO op to `ops`, extending the previous one if it is a ZERO.
+pub fn push_zero(ops: &mut Vec<PatchOp>, len: u64) {
+    match ops.last_mut() {
+        Some(PatchOp::Zero { len: prev_len }) => *prev_len += len,
+        _ => ops.push(PatchOp::Zero { len }),
+    }
+}
+
+/// Append a COPY_OUTPUT op to `ops`, extending the previous one instead
+/// when it ends right before this one in the same file.
+pub fn push_output_copy(ops: &mut Vec<PatchOp>, op: PatchOp) {
+    if let (
+        Some(PatchOp::CopyOutput {
+            path: prev_path,
+            offset: prev_offset,
+            len: prev_len,
+        }),
+        PatchOp::CopyOutput { path, offset, len },
+    ) = (ops.last_mut(), &op)
+    {
+        if *prev_path == *path && prev_offset.checked_add(*prev_len) == Some(*offset) {
+            *prev_len += len;
+            return;
+        }
     }
+    ops.push(op);
+}
 
-    Ok(manifest)
+/// Walk a directory and collect all file blocks with their paths, as
+/// [`hash_file_blocks`] does.
+pub fn scan_tree(root: &Path, block_size: usize, sketches: bool) -> Result<HashMap<String, Vec<BlockHash>>> {
+    let mut result = HashMap::new();
+
+    let mut entries: Vec<_> = WalkDir::new(root)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .collect();
+
+    entries.sort_by(|a, b| a.path().cmp(b.path()));
+
+    let hashed: Vec<(String, Vec<BlockHash>)> = entries
+        .par_iter()
+        .map(|entry| {
+            let full_path = entry.path();
+            let rel_path = full_path
+                .strip_prefix(root)
+                .map_err(|e| PatchError::Io(std::io::Error::other(e)))?;
+            Ok((encode_path(rel_path)?, hash_file_blocks(full_path, block_size, sketches)?))
+        })
+        .collect::<Result<_>>()?;
+    result.extend(hashed);
+
+    Ok(result)
+}
+
+/// Manifest entries for every directory below `dst_root`, sorted.
+pub fn dir_entries(dst_root: &Path) -> Result<Vec<ManifestEntry>> {
+    list_dirs_sorted(dst_root)?
+        .iter()
+        .map(|dir| {
+            Ok(ManifestEntry {
+                path: encode_path(dir)?,
+                entry_type: "dir".to_string(),
+                mode: 0o755,
+                mtime: 0,
+                sha256: None,
+                renamed_from: None,
+                ops: Vec::new(),
+            })
+        })
+        .collect()
 }
 
 /// List all files in a directory sorted lexically.
-fn list_files_sorted(root: &Path) -> Result<Vec<String>> {
+pub fn list_files_sorted(root: &Path) -> Result<Vec<PathBuf>> {
     let mut files = Vec::new();
 
     for entry in WalkDir::new(root)
@@ -160,8 +551,8 @@ fn list_files_sorted(root: &Path) -> Result<Vec<String>> {
         let path = entry.path();
         let rel = path
             .strip_prefix(root)
-            .map_err(|e| PatchError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
-        files.push(rel.to_string_lossy().to_string());
+            .map_err(|e| PatchError::Io(std::io::Error::other(e)))?;
+        files.push(rel.to_path_buf());
     }
 
     files.sort();
@@ -169,7 +560,7 @@ fn list_files_sorted(root: &Path) -> Result<Vec<String>> {
 }
 
 /// List all directories in a directory sorted lexically.
-fn list_dirs_sorted(root: &Path) -> Result<Vec<String>> {
+fn list_dirs_sorted(root: &Path) -> Result<Vec<PathBuf>> {
     let mut dirs = Vec::new();
 
     for entry in WalkDir::new(root)
@@ -183,10 +574,186 @@ fn list_dirs_sorted(root: &Path) -> Result<Vec<String>> {
         }
         let rel = path
             .strip_prefix(root)
-            .map_err(|e| PatchError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
-        dirs.push(rel.to_string_lossy().to_string());
+            .map_err(|e| PatchError::Io(std::io::Error::other(e)))?;
+        dirs.push(rel.to_path_buf());
     }
 
     dirs.sort();
     Ok(dirs)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn copy(src: &str, block_index: u64, len: usize) -> PatchOp {
+        PatchOp::Copy {
+            src: src.to_string(),
+            block_index,
+            len,
+        }
+    }
+
+    fn copies(ops: &[PatchOp]) -> Vec<(&str, u64, usize)> {
+        ops.iter()
+            .map(|op| match op {
+                PatchOp::Copy { src, block_index, len } => (src.as_str(), *block_index, *len),
+                other => panic!("not a COPY: {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn push_copy_merges_only_continuing_ranges() {
+        let mut ops = Vec::new();
+        push_copy(&mut ops, copy("a", 0, 8), 4);
+        push_copy(&mut ops, copy("a", 2, 4), 4); // Continues
+        push_copy(&mut ops, copy("a", 4, 4), 4); // Skips block 3
+        push_copy(&mut ops, copy("b", 5, 4), 4); // Another file
+        push_copy(&mut ops, copy("b", 6, 2), 4); // Continues, ends mid-block
+        push_copy(&mut ops, copy("b", 7, 4), 4); // Previous ends mid-block
+        push_copy(&mut ops, copy("b", 7, 4), 4); // Same block again
+        assert_eq!(
+            copies(&ops),
+            vec![("a", 0, 12), ("a", 4, 4), ("b", 5, 6), ("b", 7, 4), ("b", 7, 4)]
+        );
+    }
+
+    #[test]
+    fn fill_pattern_finds_the_shortest_period() {
+        assert_eq!(fill_pattern(&[0; 4096]), Some(&[0][..]));
+        assert_eq!(fill_pattern(&b"abc".repeat(1000)), Some(&b"abc"[..]));
+        // The last repeat may be cut short
+        assert_eq!(fill_pattern(b"abcabcab"), Some(&b"abc"[..]));
+        assert_eq!(fill_pattern(&b"abab".repeat(8)), Some(&b"ab"[..]));
+        // The pattern must repeat at least twice
+        assert_eq!(fill_pattern(b"abca"), None);
+        assert_eq!(fill_pattern(b""), None);
+        let long = noise(MAX_FILL_PATTERN + 1, 1);
+        assert_eq!(fill_pattern(&long.repeat(4)), None);
+        assert_eq!(fill_pattern(&noise(4096, 2)), None);
+    }
+
+    #[test]
+    fn push_fill_merges_runs_continuing_at_another_phase() {
+        // 4096 is not a multiple of 3, so each block starts at a new phase
+        let run = b"abc".repeat(4096);
+        let mut ops = Vec::new();
+        for block in run.chunks(4096) {
+            push_fill(&mut ops, fill_pattern(block).unwrap(), block.len() as u64);
+        }
+        assert!(matches!(&ops[..], [PatchOp::Fill { pattern, len: 12288 }] if pattern == b"abc"));
+
+        // Resuming at the wrong phase, or another pattern, starts a FILL
+        push_fill(&mut ops, b"bca", 4096);
+        push_fill(&mut ops, b"ab", 4096);
+        push_fill(&mut ops, b"ba", 4096);
+        push_fill(&mut ops, b"ab", 4096);
+        let fills: Vec<(&[u8], u64)> = ops
+            .iter()
+            .map(|op| match op {
+                PatchOp::Fill { pattern, len } => (&pattern[..], *len),
+                other => panic!("not a FILL: {:?}", other),
+            })
+            .collect();
+        assert_eq!(fills, [(&b"abc"[..], 12288), (b"bca", 4096), (b"ab", 4096), (b"ba", 4096), (b"ab", 4096)]);
+    }
+
+    /// `len` bytes of xorshift noise.
+    fn noise(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn blocks_are_sketched_only_when_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f");
+        std::fs::write(&path, noise(2 * 4096 + 100, 1)).unwrap();
+
+        let plain = hash_file_blocks(&path, 4096, false).unwrap();
+        assert!(plain.iter().all(|block| block.sketch.is_none()));
+        let sketched = hash_file_blocks(&path, 4096, true).unwrap();
+        let has_sketch: Vec<bool> = sketched.iter().map(|block| block.sketch.is_some()).collect();
+        assert_eq!(has_sketch, [true, true, false]);
+
+        let index = SourceIndex::build(dir.path(), 4096, false).unwrap();
+        assert!(index.sketches.is_empty());
+        let samples = sample_blocks(&mut File::open(&path).unwrap(), 4096, false).unwrap();
+        assert!(samples.iter().all(|(_, sketch)| sketch.is_none()));
+    }
+
+    #[test]
+    fn xor_deltas_reuse_open_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocks: Vec<Vec<u8>> = (0..3).map(|i| noise(4096, i + 1)).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            std::fs::write(dir.path().join(format!("f{}", i)), block).unwrap();
+        }
+        let index = SourceIndex::build(dir.path(), 4096, true).unwrap();
+        let mut open = OpenSources::default();
+
+        for (i, block) in blocks.iter().enumerate().chain(blocks.iter().enumerate()) {
+            let mut changed = block.clone();
+            changed[100..200].fill(0);
+            let (source, delta) = index.xor_delta(&changed, None, &mut open).unwrap().expect("similar block");
+            assert_eq!((source.src, source.block_index), (format!("f{}", i), 0));
+            assert_eq!(delta[100..200], block[100..200]);
+            assert!(delta[..100].iter().chain(&delta[200..]).all(|&b| b == 0));
+            assert_eq!(open.files[0].0, i);
+            assert!(open.files.len() <= OPEN_SOURCES);
+        }
+        assert!(index.xor_delta(&noise(4096, 9), None, &mut open).unwrap().is_none());
+    }
+
+    #[test]
+    fn xor_deltas_need_few_changed_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = noise(2 * 4096, 4);
+        std::fs::write(dir.path().join("f"), &data).unwrap();
+        let index = SourceIndex::build(dir.path(), 4096, true).unwrap();
+        let mut open = OpenSources::default();
+        let edited = |changed: usize| {
+            let mut block = data[4096..].to_vec();
+            block[..changed].iter_mut().for_each(|b| *b = !*b);
+            block
+        };
+
+        let (source, delta) = index.xor_delta(&edited(1000), None, &mut open).unwrap().expect("similar block");
+        assert_eq!((source.src.as_str(), source.block_index), ("f", 1));
+        assert_eq!(delta.iter().filter(|&&b| b != 0).count(), 1000);
+        // Over a quarter of the block changed is not worth a delta
+        assert!(index.xor_delta(&edited(1025), None, &mut open).unwrap().is_none());
+        // Shifted by a byte, the block shares its windows but not its bytes
+        assert!(index.xor_delta(&data[1..4097], None, &mut open).unwrap().is_none());
+    }
+
+    #[test]
+    fn pairs_use_the_blocks_indexed_up_front() {
+        let dir = tempfile::tempdir().unwrap();
+        let (x, y) = (noise(4096, 1), noise(4096, 2));
+        std::fs::write(dir.path().join("a"), &y).unwrap();
+        std::fs::write(dir.path().join("b"), [&x[..], &y[..]].concat()).unwrap();
+        let index = SourceIndex::build(dir.path(), 4096, true).unwrap();
+        // Matching must not read the source tree again
+        std::fs::remove_file(dir.path().join("a")).unwrap();
+        std::fs::remove_file(dir.path().join("b")).unwrap();
+
+        let pair = index.pair("b", &[(sha256_hex(&x), sketch(&x))]).expect("paired");
+        assert_eq!(pair.src, "b");
+        let y_hash = sha256_hex(&y);
+        let copy = |pair| match index.paired_copy_op(&y_hash, pair) {
+            Some(PatchOp::Copy { src, block_index, .. }) => (src, block_index),
+            other => panic!("not a COPY: {:?}", other),
+        };
+        assert_eq!(copy(Some(&pair)), ("b".to_string(), 1));
+        assert_eq!(copy(None), ("a".to_string(), 0));
+        assert_eq!(index.in_pair(&sketch(&y).unwrap(), &pair), Some((pair.path_idx, 1)));
+    }
+}
@@ -0,0 +1,263 @@
+//! Copying runs of COPY blocks from a source file into an output file.
+//!
+//! On Linux a run is first cloned with `FICLONERANGE`, which shares extents
+//! on filesystems that support it (btrfs, XFS) and costs neither time nor
+//! space, then copied in the kernel with `copy_file_range`. Whatever those
+//! cannot do (other filesystems, unaligned ranges, other platforms) is
+//! copied through a buffer.
+//!
+//! With the `io-uring` feature, shorter runs are instead queued on the
+//! worker's ring and executed in batches (see [`crate::uring`]).
+
+use crate::types::{PatchError, Result};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Runs at least this long are cloned or kernel-copied rather than batched.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+const FAST_COPY_MIN: u64 = 1 << 20;
+
+/// Per-worker copy state, reused across the files it rebuilds.
+pub(crate) struct Copier {
+    fast_copy: bool,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    ring: Option<crate::uring::Ring>,
+}
+
+impl Copier {
+    /// `fast_copy` allows the clone and `copy_file_range` paths.
+    pub fn new(fast_copy: bool) -> Self {
+        Self {
+            fast_copy,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            ring: crate::uring::Ring::new(),
+        }
+    }
+
+    /// Start writing `dst` from its beginning.
+    pub fn output<'a>(&'a mut self, dst: &'a File) -> Output<'a> {
+        Output {
+            dst,
+            pos: 0,
+            seek_needed: false,
+            hole_at_end: false,
+            fast_copy: self.fast_copy,
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            batch: self.ring.as_mut().and_then(|ring| ring.batch(dst)),
+        }
+    }
+}
+
+/// Sequential writer for one output file that may defer COPY runs.
+/// Nothing is guaranteed written until [`Output::finish`] returns.
+pub(crate) struct Output<'a> {
+    dst: &'a File,
+    pos: u64,          // Bytes produced so far
+    seek_needed: bool, // File position lags `pos` after batched copies or holes
+    hole_at_end: bool, // The file must be extended to `pos`
+    fast_copy: bool,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    batch: Option<crate::uring::Batch<'a>>,
+}
+
+impl<'a> Output<'a> {
+    /// Append `len` bytes at `src_offset` in `src`.
+    pub fn copy(&mut self, src: &'a File, src_offset: u64, len: u64) -> Result<()> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(batch) = &mut self.batch {
+            if !(self.fast_copy && len >= FAST_COPY_MIN) {
+                batch.copy(src, src_offset, self.pos, len)?;
+                self.pos += len;
+                self.seek_needed = true;
+                self.hole_at_end &= len == 0;
+                return Ok(());
+            }
+        }
+
+        self.sync_position()?;
+        copy_range(src, src_offset, self.dst, len, self.fast_copy)?;
+        self.pos += len;
+        self.hole_at_end &= len == 0;
+        Ok(())
+    }
+
+    /// Append `len` zero bytes as a hole. The output is a new file, so
+    /// skipping the range leaves it unallocated.
+    pub fn zero(&mut self, len: u64) {
+        if len > 0 {
+            self.pos += len;
+            self.seek_needed = true;
+            self.hole_at_end = true;
+        }
+    }
+
+    /// Append `len` bytes this output already holds at `offset`. `this` is
+    /// a separate read handle on the output file.
+    pub fn copy_back(&mut self, this: &'a File, offset: u64, len: u64) -> Result<()> {
+        if offset.checked_add(len).is_none_or(|end| end > self.pos) {
+            return Err(PatchError::Format(format!(
+                "COPY_OUTPUT of {} bytes at {} reads past the {} bytes written",
+                len, offset, self.pos
+            )));
+        }
+
+        // Deferred copies may still be filling the range
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(batch) = &mut self.batch {
+            batch.flush()?;
+        }
+        self.copy(this, offset, len)
+    }
+
+    /// Append `data`.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.sync_position()?;
+        let mut dst = self.dst;
+        dst.write_all(data)?;
+        self.pos += data.len() as u64;
+        self.hole_at_end &= data.is_empty();
+        Ok(())
+    }
+
+    /// Complete any deferred copies.
+    pub fn finish(self) -> Result<()> {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Some(mut batch) = self.batch {
+            batch.flush()?;
+        }
+        if self.hole_at_end {
+            self.dst.set_len(self.pos)?;
+        }
+        Ok(())
+    }
+
+    fn sync_position(&mut self) -> Result<()> {
+        if self.seek_needed {
+            let mut dst = self.dst;
+            dst.seek(SeekFrom::Start(self.pos))?;
+            self.seek_needed = false;
+        }
+        Ok(())
+    }
+}
+
+/// Copy `len` bytes at `src_offset` in `src` to the current position of
+/// `dst`, leaving `dst` positioned after them. `kernel` allows the clone
+/// and `copy_file_range` paths.
+fn copy_range(mut src: &File, src_offset: u64, mut dst: &File, len: u64, kernel: bool) -> io::Result<()> {
+    let mut done = 0;
+    if kernel {
+        done = kernel_copy(src, src_offset, dst, len);
+    }
+    if done == len {
+        return Ok(());
+    }
+
+    src.seek(SeekFrom::Start(src_offset + done))?;
+    let left = len - done;
+    let copied = io::copy(&mut src.take(left), &mut dst)?;
+    if copied != left {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("COPY source ends {} bytes early", left - copied),
+        ));
+    }
+    Ok(())
+}
+
+/// Copy as much of the range as the kernel will, returning the bytes done.
+#[cfg(target_os = "linux")]
+fn kernel_copy(src: &File, src_offset: u64, mut dst: &File, len: u64) -> u64 {
+    use std::os::fd::AsRawFd;
+
+    let Ok(dst_offset) = dst.stream_position() else {
+        return 0;
+    };
+
+    // Clones extend `dst` but do not move its position
+    let range = libc::file_clone_range {
+        src_fd: src.as_raw_fd() as i64,
+        src_offset,
+        src_length: len,
+        dest_offset: dst_offset,
+    };
+    let rc = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONERANGE, &range) };
+    if rc == 0 && dst.seek(SeekFrom::Start(dst_offset + len)).is_ok() {
+        return len;
+    }
+
+    // `copy_file_range` advances `dst` itself; stop at the first error or
+    // early end and let the buffered copy finish or report it
+    let mut offset = src_offset as libc::loff_t;
+    let mut done = 0;
+    while done < len {
+        let chunk = (len - done).min(1 << 30) as usize;
+        let n = unsafe {
+            libc::copy_file_range(
+                src.as_raw_fd(),
+                &mut offset,
+                dst.as_raw_fd(),
+                std::ptr::null_mut(),
+                chunk,
+                0,
+            )
+        };
+        if n <= 0 {
+            break;
+        }
+        done += n as u64;
+    }
+    done
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_copy(_src: &File, _src_offset: u64, _dst: &File, _len: u64) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `len` bytes of xorshift noise.
+    fn noise(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn kernel_copy_matches_buffered_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = noise(5 * 4096, 1);
+        std::fs::write(dir.path().join("src"), &data).unwrap();
+        let src = File::open(dir.path().join("src")).unwrap();
+
+        // Unaligned on both sides and spanning several blocks
+        let (src_offset, len) = (1000, 3 * 4096 + 123);
+        let mut outputs = Vec::new();
+        for kernel in [true, false] {
+            let path = dir.path().join(format!("dst-{}", kernel));
+            let mut dst = File::options().create_new(true).read(true).write(true).open(&path).unwrap();
+            dst.write_all(b"head").unwrap();
+            copy_range(&src, src_offset, &dst, len, kernel).unwrap();
+            assert_eq!(dst.stream_position().unwrap(), 4 + len);
+            dst.write_all(b"tail").unwrap();
+            outputs.push(std::fs::read(&path).unwrap());
+        }
+        let range = &data[src_offset as usize..(src_offset + len) as usize];
+        assert!(outputs[0] == [&b"head"[..], range, b"tail"].concat());
+        assert!(outputs[0] == outputs[1]);
+
+        // Reading past the end of the source is an error either way
+        for kernel in [true, false] {
+            let dst = tempfile::tempfile_in(dir.path()).unwrap();
+            assert!(copy_range(&src, 4 * 4096, &dst, 2 * 4096, kernel).is_err());
+        }
+    }
+}
@@ -2,170 +2,797 @@
 //!
 //! High-level API for creating and applying patches.
 
+pub mod codec;
 pub mod compress;
 pub mod diff;
+mod fastcopy;
+pub mod manifest;
 pub mod patch;
+pub mod paths;
+mod pipeline;
+pub mod rootdir;
+mod schedule;
+mod sparse;
 pub mod types;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 pub mod verify;
 
 pub use types::{
-    ApplyPatchOptions, MakePatchOptions, Patch, PatchError, PatchOp, Result,
+    ApplyPatchOptions, CompressionMode, IncompressibleCheck, MakePatchOptions, Patch, PatchError,
+    PatchLimits, PatchOp, Result,
 };
 
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 /// Create a patch file that transforms `src_root` into `dst_root`.
 ///
 /// - `src_root` and `dst_root` are directory roots.
-/// - `output_patch` is the file to write the patch into (created/truncated).
+/// - `output_patch` is the file to write the patch into (created/truncated),
+///   or `-` to write to stdout.
 /// - `opts` controls block size and compression behavior.
+///
+/// ADD payloads are streamed straight to the output, so memory use does not
+/// grow with the size of the data section.
 pub fn make_patch(
     src_root: &Path,
     dst_root: &Path,
     output_patch: &Path,
     opts: &MakePatchOptions,
 ) -> Result<()> {
-    // Generate manifest
-    let mut manifest = diff::generate_manifest(src_root, dst_root, opts.block_size)?;
+    if output_patch == Path::new("-") {
+        let stdout = std::io::stdout();
+        make_patch_to_writer(src_root, dst_root, BufWriter::new(stdout.lock()), opts)
+    } else {
+        let out_file = File::create(output_patch)?;
+        make_patch_to_writer(src_root, dst_root, BufWriter::new(out_file), opts)
+    }
+}
+
+/// Create a patch like [`make_patch`], writing it to any `Write` sink.
+///
+/// Each destination file is paired with the source file it most likely
+/// came from (see [`diff::SourceIndex::pair`]), then read once: its blocks
+/// are hashed, matched against the source and, when new, handed straight
+/// to compression.
+/// Hashing and compression run on a pool of `opts.threads` workers; the
+/// output does not depend on the thread count.
+pub fn make_patch_to_writer<W: Write>(
+    src_root: &Path,
+    dst_root: &Path,
+    writer: W,
+    opts: &MakePatchOptions,
+) -> Result<()> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.threads)
+        .build()
+        .map_err(|e| PatchError::Unsupported(format!("Cannot start worker pool: {}", e)))?;
 
-    // Create patch and populate data section
-    let mut patch = Patch::new();
+    // Index the source; destination files are diffed as they are read
+    let src_index = pool.install(|| diff::SourceIndex::build(src_root, opts.block_size, opts.similar_blocks))?;
+    let dst_files = diff::list_files_sorted(dst_root)?;
+    let mut manifest = types::Manifest::new();
 
-    for entry in &mut manifest.entries {
-        if entry.entry_type == "file" {
-            let dst_file_path = dst_root.join(&entry.path);
-            let mut file = File::open(&dst_file_path)?;
-            let mut buffer = vec![0u8; opts.block_size];
+    // Stream the data section; the manifest is written last
+    let mut writer = patch::PatchWriter::new(writer)?;
+    let grouped = opts.zstd_level >= 0 && opts.compression_mode != CompressionMode::Blocks;
+    let seekable = opts.compression_mode == CompressionMode::Seekable;
+    let mut group = pipeline::AddGroup::default();
+    manifest.compression_mode = if grouped {
+        opts.compression_mode
+    } else {
+        CompressionMode::Blocks
+    };
+    let mut pipeline = pipeline::BlobPipeline::new(&pool, opts);
+    // Only one of these is filled: repeated new blocks are copied from the
+    // output or, without output copies, read again from their blob
+    let mut output_index = diff::OutputIndex::default();
+    let mut add_index = pipeline::AddIndex::default();
+    // Source files read for XOR deltas stay open from block to block
+    let mut open_sources = diff::OpenSources::default();
+    let chunk_size = opts.block_size.max(1) * DIFF_CHUNK_BLOCKS;
+    let mut chunk = vec![0u8; chunk_size];
 
-            for op in &mut entry.ops {
-                match op {
-                    PatchOp::Add {
-                        data_offset,
+    for rel_path in dst_files {
+        let mut file = File::open(dst_root.join(&rel_path))?;
+        let file_codec = opts.codec_for(&rel_path);
+        let entry_path = paths::encode_path(&rel_path)?;
+
+        // Deltas are taken against the source file this one came from,
+        // which for a renamed file is at another path
+        let pair = if opts.detect_renames {
+            let samples = diff::sample_blocks(&mut file, opts.block_size.max(1), opts.similar_blocks)?;
+            src_index.pair(&entry_path, &samples)
+        } else {
+            None
+        };
+        let base_path = pair.as_ref().map_or(&entry_path, |pair| &pair.src).clone();
+
+        // With patch-from, the file's ADD data becomes one blob compressed
+        // against the old version of the file
+        let base_data = if opts.patch_from && file_codec.id() == codec::ZSTD_ID {
+            let dst_len = file.metadata()?.len();
+            let base_file = src_root.join(paths::decode_relative_path(&base_path)?);
+            read_patch_from_base(&base_file, dst_len, opts.patch_from_max_size)?
+        } else {
+            None
+        };
+        let mut file_group = pipeline::AddGroup {
+            codec: Some(file_codec),
+            ..Default::default()
+        };
+        // Blocks mode: the current run of new blocks, written as one blob
+        let mut run = pipeline::AddGroup {
+            codec: Some(file_codec),
+            ..Default::default()
+        };
+        if grouped && group.codec.is_some_and(|c| c.id() != file_codec.id()) {
+            if let Some(blob) = group.take(opts, None, seekable) {
+                pipeline.push(blob, &mut writer, &mut manifest)?;
+            }
+        }
+        group.codec = Some(file_codec);
+
+        let entry_idx = manifest.entries.len();
+        manifest.entries.push(types::ManifestEntry {
+            path: entry_path.clone(),
+            entry_type: "file".to_string(),
+            mode: 0o644,
+            mtime: 0,
+            sha256: None,
+            renamed_from: (base_path != entry_path).then(|| base_path.clone()),
+            ops: Vec::new(),
+        });
+
+        let mut out_pos = 0u64;
+        let mut holes = sparse::Holes::default();
+        let block_size = opts.block_size.max(1) as u64;
+        loop {
+            // Whole blocks inside a hole become zeros without being read
+            let hole = holes.at(&file, out_pos) / block_size * block_size;
+            if hole > 0 {
+                if let Some(blob) = run.take(opts, None, false) {
+                    pipeline.push(blob, &mut writer, &mut manifest)?;
+                }
+                diff::push_zero(&mut manifest.entries[entry_idx].ops, hole);
+                out_pos += hole;
+                file.seek(std::io::SeekFrom::Start(out_pos))?;
+                continue;
+            }
+
+            let n = diff::read_full(&mut file, &mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            let hashes = pool.install(|| diff::hash_blocks(&chunk[..n], opts.block_size));
+
+            for (block, sha256) in chunk[..n].chunks(opts.block_size).zip(hashes) {
+                let block_offset = out_pos;
+                out_pos += block.len() as u64;
+                if diff::is_zero(block) {
+                    if let Some(blob) = run.take(opts, None, false) {
+                        pipeline.push(blob, &mut writer, &mut manifest)?;
+                    }
+                    diff::push_zero(&mut manifest.entries[entry_idx].ops, block.len() as u64);
+                    continue;
+                }
+                if let Some(pattern) = diff::fill_pattern(block) {
+                    if let Some(blob) = run.take(opts, None, false) {
+                        pipeline.push(blob, &mut writer, &mut manifest)?;
+                    }
+                    diff::push_fill(&mut manifest.entries[entry_idx].ops, pattern, block.len() as u64);
+                    continue;
+                }
+                if let Some(copy) = src_index.paired_copy_op(&sha256, pair.as_ref()) {
+                    if let Some(blob) = run.take(opts, None, false) {
+                        pipeline.push(blob, &mut writer, &mut manifest)?;
+                    }
+                    diff::push_copy(&mut manifest.entries[entry_idx].ops, copy, opts.block_size);
+                    continue;
+                }
+
+                let entries = &manifest.entries;
+                if let Some(copy) = output_index.copy_op(&sha256, block.len(), |idx| entries[idx].path.clone()) {
+                    if let Some(blob) = run.take(opts, None, false) {
+                        pipeline.push(blob, &mut writer, &mut manifest)?;
+                    }
+                    diff::push_output_copy(&mut manifest.entries[entry_idx].ops, copy);
+                    continue;
+                }
+                if let Some(origin) = add_index.find(&sha256) {
+                    if let Some(blob) = run.take(opts, None, false) {
+                        pipeline.push(blob, &mut writer, &mut manifest)?;
+                    }
+                    add_index.push_ref(&mut manifest, entry_idx, origin, block.len() as u64);
+                    continue;
+                }
+                // Blocks resembling a source block are stored as their XOR
+                // with it, which compresses to little more than the changes
+                let delta = if opts.similar_blocks && base_data.is_none() && block.len() == opts.block_size {
+                    src_index.xor_delta(block, pair.as_ref(), &mut open_sources)?
+                } else {
+                    None
+                };
+                let (xor, data) = match &delta {
+                    Some((xor, delta)) => (Some(xor), delta.as_slice()),
+                    None => (None, block),
+                };
+                pipeline.sample(data, &mut writer, &mut manifest)?;
+
+                let target = if base_data.is_some() {
+                    &mut file_group
+                } else if grouped {
+                    &mut group
+                } else {
+                    &mut run
+                };
+
+                // Extend the previous ADD while its data ends the buffered
+                // blob; otherwise start a new op. Offsets are filled in when
+                // the blob is written.
+                let ops = &mut manifest.entries[entry_idx].ops;
+                let last = ops.len().checked_sub(1).map(|op_idx| (entry_idx, op_idx));
+                let offset = match ops.last_mut() {
+                    Some(PatchOp::Add {
                         data_length,
-                        compressed,
-                        compression,
-                        zstd_level,
-                    } => {
-                        let mut block_data = vec![0u8; *data_length as usize];
-                        file.read_exact(&mut block_data)?;
-
-                        let payload = if opts.zstd_level >= 0 {
-                            *compressed = true;
-                            *compression = Some("zstd".to_string());
-                            *zstd_level = Some(opts.zstd_level);
-                            compress::compress(&block_data, opts.zstd_level)?
-                        } else {
-                            block_data
-                        };
-
-                        *data_offset = patch::append_add_blob(&mut patch, &payload, *compressed, opts.zstd_level)?;
+                        xor: prev_xor,
+                        ..
+                    }) if target.members.last() == last.as_ref()
+                        && diff::xor_continues(prev_xor.as_ref(), *data_length, xor, opts.block_size) =>
+                    {
+                        *data_length += block.len() as u64;
+                        *data_length - block.len() as u64
                     }
-                    PatchOp::Copy { .. } => {
-                        // Already set in generate_manifest
+                    _ => {
+                        let compressed = file_codec.id() != codec::STORE_ID;
+                        target.members.push((entry_idx, ops.len()));
+                        ops.push(PatchOp::Add {
+                            data_offset: 0,
+                            data_length: block.len() as u64,
+                            compressed,
+                            compression: compressed.then(|| file_codec.name().to_string()),
+                            zstd_level: compressed.then_some(opts.zstd_level),
+                            blob_offset: target.data.len() as u64,
+                            base: base_data.is_some().then(|| base_path.clone()),
+                            xor: xor.cloned(),
+                        });
+                        0
                     }
+                };
+                target.data.extend_from_slice(data);
+                // Patch-from blobs are not shared: they decode against their
+                // own file's base, as XOR deltas do against their source
+                if opts.output_copies {
+                    output_index.insert(sha256, entry_idx, block_offset);
+                } else if opts.dedupe && base_data.is_none() && xor.is_none() {
+                    add_index.insert(sha256, (entry_idx, ops.len() - 1, offset));
                 }
+
+                let full = if grouped && base_data.is_none() {
+                    group.take_if_full(opts, opts.solid_group_size, seekable)
+                } else {
+                    run.take_if_full(opts, MAX_ADD_RUN, false)
+                };
+                if let Some(blob) = full {
+                    pipeline.push(blob, &mut writer, &mut manifest)?;
+                }
+            }
+
+            if n < chunk_size {
+                break;
             }
         }
+
+        if let Some(blob) = file_group.take(opts, base_data, false) {
+            pipeline.push(blob, &mut writer, &mut manifest)?;
+        }
+        if let Some(blob) = run.take(opts, None, false) {
+            pipeline.push(blob, &mut writer, &mut manifest)?;
+        }
     }
 
-    patch.manifest = manifest;
+    if let Some(blob) = group.take(opts, None, seekable) {
+        pipeline.push(blob, &mut writer, &mut manifest)?;
+    }
+    pipeline.flush(&mut writer, &mut manifest)?;
+    add_index.resolve(&mut manifest);
 
-    // Write patch file
-    let mut out_file = File::create(output_patch)?;
-    patch::write_patch(&mut out_file, &patch)?;
+    // Also add directories
+    manifest.entries.extend(diff::dir_entries(dst_root)?);
+    writer.finish(&manifest)?;
 
     Ok(())
 }
 
+/// Destination blocks read and hashed together.
+const DIFF_CHUNK_BLOCKS: usize = 256;
+
+/// Largest run of new blocks stored as one blob in blocks mode.
+const MAX_ADD_RUN: usize = 4 << 20;
+
+/// Read the old version of a file for patch-from compression, if it exists
+/// and both versions fit within `max_size`.
+fn read_patch_from_base(src_path: &Path, dst_len: u64, max_size: u64) -> Result<Option<Vec<u8>>> {
+    let meta = match fs::metadata(src_path) {
+        Ok(meta) if meta.is_file() => meta,
+        _ => return Ok(None),
+    };
+    if meta.len() == 0 || meta.len() > max_size || dst_len > max_size {
+        return Ok(None);
+    }
+    Ok(Some(fs::read(src_path)?))
+}
+
 /// Apply a patch file to `target_root`.
 /// - `patch_path` is the path to the patch file.
-/// - `opts` controls verification and atomic application.
+/// - `opts` controls verification, atomic application and parallelism.
+///
+/// The manifest is streamed entry by entry, twice: once to validate every
+/// path before any I/O, then to apply. With `opts.atomic`, files are
+/// rebuilt on up to `opts.threads` workers, each into a temporary sibling
+/// that is renamed into place once no other entry still needs to read the
+/// original; entries copying from other rebuilt files then run in manifest
+/// order. Without it entries are applied one after another.
 pub fn apply_patch(
     target_root: &Path,
     patch_path: &Path,
     opts: &ApplyPatchOptions,
 ) -> Result<()> {
-    // Read patch file
-    let mut patch_file = File::open(patch_path)?;
-    let patch = patch::read_patch(&mut patch_file)?;
+    // Open patch file; blobs are fetched on demand
+    let open = || -> Result<BufReader<File>> { Ok(BufReader::new(File::open(patch_path)?)) };
+    let mut reader = patch::PatchReader::new(open()?, &opts.limits)?;
+    // Loads the dictionary; the entries are streamed below
+    reader.read_manifest_header()?;
 
-    // Ensure target root exists
-    fs::create_dir_all(target_root)?;
+    // Reject paths that would escape the target, and COPY_OUTPUT ops
+    // reading files not rebuilt yet, before doing any I/O. Note which files
+    // read target paths besides their own, and which copy from other
+    // rebuilt files
+    let mut reads = HashMap::new();
+    let mut deferred = HashSet::new();
+    let mut outputs = HashSet::new();
+    let mut files = HashSet::new();
+    for (index, entry) in reader.entries(open()?)?.enumerate() {
+        let entry = entry?;
+        paths::validate_entry(&entry)?;
+        if entry.entry_type != "file" {
+            continue;
+        }
+        let copied = other_outputs(&entry)?;
+        if let Some(path) = copied.iter().filter(|path| !files.contains(*path)).min() {
+            return Err(PatchError::Format(format!(
+                "COPY_OUTPUT reads {} before it is rebuilt",
+                path.display()
+            )));
+        }
+        files.insert(paths::decode_relative_path(&entry.path)?);
+        if !copied.is_empty() {
+            deferred.insert(index);
+            outputs.extend(copied);
+        }
+        if opts.atomic {
+            let read = other_reads(&entry)?;
+            if !read.is_empty() {
+                reads.insert(index, read);
+            }
+        }
+    }
 
-    // Process manifest entries
-    for entry in &patch.manifest.entries {
-        let full_path = target_root.join(&entry.path);
+    // Ensure target root exists; everything below it goes through the handle
+    fs::create_dir_all(target_root)?;
+    let root = rootdir::RootDir::open(target_root)?;
+    let total_output = AtomicU64::new(0);
+    let entries = reader.entries(open()?)?;
 
-        match entry.entry_type.as_str() {
-            "dir" => {
-                fs::create_dir_all(&full_path)?;
+    if !opts.atomic {
+        let mut worker = Worker {
+            reader,
+            copier: fastcopy::Copier::new(opts.fast_copy),
+        };
+        // Files are rebuilt in place, so earlier outputs are at their paths
+        let mut rebuilt = HashSet::new();
+        for entry in entries {
+            let entry = entry?;
+            let rel_path = paths::decode_relative_path(&entry.path)?;
+            match entry.entry_type.as_str() {
+                "dir" => root.create_dir_all(&rel_path)?,
+                "file" => {
+                    let sources = open_outputs(&root, &entry, |path| Ok(rebuilt.get(path).cloned()))?;
+                    apply_file(&root, &entry, &rel_path, &mut worker, &sources, opts, &total_output)?;
+                    if outputs.contains(&rel_path) {
+                        rebuilt.insert(rel_path);
+                    }
+                }
+                _ => {}
             }
-            "file" => {
-                // Ensure parent dir exists
-                if let Some(parent) = full_path.parent() {
-                    fs::create_dir_all(parent)?;
+        }
+        return Ok(());
+    }
+
+    let tracker = schedule::CommitTracker::new(reads.values(), &outputs);
+    let budget = schedule::ByteBudget::new(opts.max_inflight_bytes);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.threads)
+        .build()
+        .map_err(|e| PatchError::Unsupported(format!("Cannot start worker pool: {}", e)))?;
+    let no_reads = HashSet::new();
+    let no_outputs = HashMap::new();
+    let commit = |path: &Path| root.rename(&temp_path_for(path), path);
+
+    pool.install(|| {
+        entries.enumerate().par_bridge().try_for_each_init(
+            || None,
+            |worker, (index, entry)| {
+                let entry = entry?;
+                let rel_path = paths::decode_relative_path(&entry.path)?;
+                if entry.entry_type != "file" {
+                    // Files create any missing parents themselves
+                    return match entry.entry_type.as_str() {
+                        "dir" => root.create_dir_all(&rel_path),
+                        _ => Ok(()),
+                    };
+                }
+                if deferred.contains(&index) {
+                    return Ok(());
                 }
 
-                // Write to temp file, then rename
-                let temp_path = if opts.atomic {
-                    format!("{}.tmp", full_path.display())
-                } else {
-                    full_path.to_string_lossy().to_string()
-                };
+                // Each worker reads blobs through its own handle
+                if worker.is_none() {
+                    *worker = Some(Worker {
+                        reader: reader.reopen(open()?),
+                        copier: fastcopy::Copier::new(opts.fast_copy),
+                    });
+                }
+                let worker = worker.as_mut().expect("created above");
+                let read = reads.get(&index).unwrap_or(&no_reads);
+                let _budget = budget.acquire(output_len(&entry))?;
+                apply_file(&root, &entry, &rel_path, worker, &no_outputs, opts, &total_output)?;
+                tracker.finish(&rel_path, read, commit)
+            },
+        )
+    })?;
+    if deferred.is_empty() {
+        return Ok(());
+    }
 
-                let mut out_file = File::create(&temp_path)?;
-
-                // Execute operations in order
-                for op in &entry.ops {
-                    match op {
-                        PatchOp::Copy {
-                            src,
-                            block_index,
-                            len,
-                        } => {
-                            let src_path = target_root.join(src);
-                            let mut src_file = File::open(&src_path)?;
-
-                            let offset = *block_index as u64 * opts.block_size as u64;
-                            src_file.seek(std::io::SeekFrom::Start(offset))?;
-
-                            let mut buf = vec![0u8; *len];
-                            src_file.read_exact(&mut buf)?;
-
-                            if opts.verify_checksums {
-                                // Optional: verify checksum if available
-                            }
-
-                            out_file.write_all(&buf)?;
-                        }
-                        PatchOp::Add {
-                            data_offset,
-                            data_length,
-                            compressed,
-                            ..
-                        } => {
-                            let payload = patch::read_add_blob(&patch, *data_offset)?;
-                            out_file.write_all(&payload)?;
-                        }
+    // Every file not copying from another output is rebuilt now; the rest
+    // run in order, so each sees the files before it
+    let mut worker = Worker {
+        reader: reader.reopen(open()?),
+        copier: fastcopy::Copier::new(opts.fast_copy),
+    };
+    for (index, entry) in reader.entries(open()?)?.enumerate() {
+        let entry = entry?;
+        if !deferred.contains(&index) {
+            continue;
+        }
+        let rel_path = paths::decode_relative_path(&entry.path)?;
+        let sources = open_outputs(&root, &entry, |path| {
+            Ok(tracker.rebuilt(path)?.map(|at| match at {
+                schedule::Rebuilt::Temporary => temp_path_for(path),
+                schedule::Rebuilt::InPlace => path.to_path_buf(),
+            }))
+        })?;
+        apply_file(&root, &entry, &rel_path, &mut worker, &sources, opts, &total_output)?;
+        tracker.finish(&rel_path, reads.get(&index).unwrap_or(&no_reads), commit)?;
+    }
+    Ok(())
+}
+
+/// Target paths a file entry reads besides its own: COPY sources,
+/// patch-from bases and XOR sources.
+fn other_reads(entry: &types::ManifestEntry) -> Result<HashSet<PathBuf>> {
+    let own = paths::decode_relative_path(&entry.path)?;
+    let mut read = HashSet::new();
+    for op in &entry.ops {
+        let srcs = match op {
+            PatchOp::Copy { src, .. } => [Some(src), None],
+            PatchOp::Add { base, xor, .. } => [base.as_ref(), xor.as_ref().map(|xor| &xor.src)],
+            PatchOp::CopyOutput { .. } | PatchOp::Zero { .. } | PatchOp::Fill { .. } => continue,
+        };
+        for src in srcs.into_iter().flatten() {
+            let src = paths::decode_relative_path(src)?;
+            if src != own {
+                read.insert(src);
+            }
+        }
+    }
+    Ok(read)
+}
+
+/// Files whose new contents a file entry copies, besides its own.
+fn other_outputs(entry: &types::ManifestEntry) -> Result<HashSet<PathBuf>> {
+    let own = paths::decode_relative_path(&entry.path)?;
+    let mut copied = HashSet::new();
+    for op in &entry.ops {
+        if let PatchOp::CopyOutput { path, .. } = op {
+            let path = paths::decode_relative_path(path)?;
+            if path != own {
+                copied.insert(path);
+            }
+        }
+    }
+    Ok(copied)
+}
+
+/// Open the other rebuilt files an entry copies from. `locate` gives where
+/// a file's new contents are, or `None` if it has not been rebuilt.
+fn open_outputs<'e>(
+    root: &rootdir::RootDir,
+    entry: &'e types::ManifestEntry,
+    mut locate: impl FnMut(&Path) -> Result<Option<PathBuf>>,
+) -> Result<HashMap<&'e str, File>> {
+    let own = paths::decode_relative_path(&entry.path)?;
+    let mut sources = HashMap::new();
+    for op in &entry.ops {
+        if let PatchOp::CopyOutput { path, .. } = op {
+            let rel_path = paths::decode_relative_path(path)?;
+            if rel_path == own || sources.contains_key(path.as_str()) {
+                continue;
+            }
+            let at = locate(&rel_path)?.ok_or_else(|| {
+                PatchError::Format(format!("COPY_OUTPUT reads {} before it is rebuilt", path))
+            })?;
+            sources.insert(path.as_str(), root.open_file(&at)?);
+        }
+    }
+    Ok(sources)
+}
+
+/// Patch reader and copy state of one apply worker.
+struct Worker<R: Read + Seek> {
+    reader: patch::PatchReader<R>,
+    copier: fastcopy::Copier,
+}
+
+/// Rebuild one file entry: into its temporary sibling when `opts.atomic`
+/// (the caller renames it), else in place. `outputs` holds the other
+/// rebuilt files its COPY_OUTPUT ops read (see [`open_outputs`]).
+fn apply_file<R: Read + Seek>(
+    root: &rootdir::RootDir,
+    entry: &types::ManifestEntry,
+    rel_path: &Path,
+    worker: &mut Worker<R>,
+    outputs: &HashMap<&str, File>,
+    opts: &ApplyPatchOptions,
+    total_output: &AtomicU64,
+) -> Result<()> {
+    // Write to temp file, then rename
+    let temp_path = if opts.atomic {
+        temp_path_for(rel_path)
+    } else {
+        rel_path.to_path_buf()
+    };
+
+    // Load patch-from bases before the output can replace them
+    let mut bases: HashMap<&str, Vec<u8>> = HashMap::new();
+    for op in &entry.ops {
+        if let PatchOp::Add { base: Some(base), .. } = op {
+            if !bases.contains_key(base.as_str()) {
+                let data = read_base(root, base, &opts.limits)?;
+                bases.insert(base, data);
+            }
+        }
+    }
+
+    // Open each COPY and XOR source once
+    let mut sources: HashMap<&str, File> = HashMap::new();
+    for op in &entry.ops {
+        let src = match op {
+            PatchOp::Copy { src, .. } => src,
+            PatchOp::Add { xor: Some(xor), .. } => &xor.src,
+            _ => continue,
+        };
+        if !sources.contains_key(src.as_str()) {
+            sources.insert(src, root.open_file(&paths::decode_relative_path(src)?)?);
+        }
+    }
+
+    // Creates missing parent directories
+    let out_file = root.create_file(&temp_path)?;
+    // COPY_OUTPUT ops reading this file use a handle of their own
+    let copies_back = entry.ops.iter().any(|op| {
+        matches!(op, PatchOp::CopyOutput { path, .. } if !outputs.contains_key(path.as_str()))
+    });
+    let this = if copies_back {
+        Some(root.open_file(&temp_path)?)
+    } else {
+        None
+    };
+    let mut out = worker.copier.output(&out_file);
+
+    // Execute operations in order
+    let mut ops = entry.ops.iter().peekable();
+    while let Some(op) = ops.next() {
+        match op {
+            PatchOp::Copy {
+                src,
+                block_index,
+                len,
+            } => {
+                let block_size = opts.block_size.max(1) as u64;
+                let offset = block_index.checked_mul(block_size).ok_or_else(|| {
+                    PatchError::Format(format!("COPY block index {} is out of range", block_index))
+                })?;
+
+                // Extend over following COPYs that continue the range in the
+                // same source (older patches have one per block)
+                let mut run_len = *len as u64;
+                while let Some(PatchOp::Copy {
+                    src: next_src,
+                    block_index: index,
+                    len,
+                }) = ops.peek()
+                {
+                    let next_index = block_index.checked_add(run_len / block_size);
+                    if next_src != src || !run_len.is_multiple_of(block_size) || Some(*index) != next_index {
+                        break;
                     }
+                    run_len = run_len.saturating_add(*len as u64);
+                    ops.next();
                 }
+                add_output(total_output, run_len, &opts.limits)?;
 
-                drop(out_file);
-
-                // Atomically rename
-                if opts.atomic {
-                    fs::rename(&temp_path, &full_path)?;
+                out.copy(&sources[src.as_str()], offset, run_len)?;
+            }
+            PatchOp::Add {
+                data_offset,
+                data_length,
+                blob_offset,
+                base,
+                xor,
+                ..
+            } => {
+                let prefix = base.as_deref().and_then(|b| bases.get(b)).map(Vec::as_slice);
+                // Repeated data may read part of another op's blob, so blocks
+                // mode goes through the range cache too
+                let mut payload = worker.reader.read_add_range(*data_offset, *blob_offset, *data_length, prefix)?;
+                if payload.len() as u64 != *data_length {
+                    return Err(PatchError::Format(format!(
+                        "ADD blob at {} has {} bytes, expected {}",
+                        data_offset,
+                        payload.len(),
+                        data_length
+                    )));
+                }
+                if let Some(xor) = xor {
+                    apply_xor(&mut payload, &sources[xor.src.as_str()], xor, opts.block_size)?;
+                }
+                add_output(total_output, *data_length, &opts.limits)?;
+                out.write(&payload)?;
+            }
+            PatchOp::Zero { len } => {
+                add_output(total_output, *len, &opts.limits)?;
+                out.zero(*len);
+            }
+            PatchOp::Fill { pattern, len } => {
+                add_output(total_output, *len, &opts.limits)?;
+                write_fill(&mut out, pattern, *len)?;
+            }
+            PatchOp::CopyOutput { path, offset, len } => {
+                add_output(total_output, *len, &opts.limits)?;
+                match outputs.get(path.as_str()) {
+                    Some(src) => out.copy(src, *offset, *len)?,
+                    None => out.copy_back(this.as_ref().expect("opened above"), *offset, *len)?,
                 }
             }
-            _ => {}
         }
     }
 
+    out.finish()
+}
+
+/// XOR `data` with the source bytes `xor` names, read from `source`.
+fn apply_xor(data: &mut [u8], mut source: &File, xor: &types::XorSource, block_size: usize) -> Result<()> {
+    let offset = xor.block_index.checked_mul(block_size.max(1) as u64).ok_or_else(|| {
+        PatchError::Format(format!("XOR block index {} is out of range", xor.block_index))
+    })?;
+    source.seek(std::io::SeekFrom::Start(offset))?;
+    let mut bytes = vec![0u8; data.len()];
+    if diff::read_full(&mut source, &mut bytes)? < bytes.len() {
+        return Err(PatchError::Format(format!(
+            "XOR source {} has fewer than {} bytes at {}",
+            xor.src,
+            bytes.len(),
+            offset
+        )));
+    }
+    for (d, b) in data.iter_mut().zip(bytes) {
+        *d ^= b;
+    }
+    Ok(())
+}
+
+/// Write `len` bytes repeating `pattern`.
+fn write_fill(out: &mut fastcopy::Output, pattern: &[u8], len: u64) -> Result<()> {
+    if pattern.is_empty() {
+        return Err(PatchError::Format("FILL op with an empty pattern".to_string()));
+    }
+    // Whole repeats, so every write starts the pattern afresh
+    let repeats = (FILL_CHUNK / pattern.len()).max(1);
+    let chunk = pattern.repeat(repeats);
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(chunk.len() as u64) as usize;
+        out.write(&chunk[..n])?;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+/// Bytes generated per write for FILL ops.
+const FILL_CHUNK: usize = 64 << 10;
+
+/// Bytes an entry's ops write; holes cost no I/O.
+fn output_len(entry: &types::ManifestEntry) -> u64 {
+    entry
+        .ops
+        .iter()
+        .map(|op| match op {
+            PatchOp::Copy { len, .. } => *len as u64,
+            PatchOp::Add { data_length, .. } => *data_length,
+            PatchOp::CopyOutput { len, .. } => *len,
+            PatchOp::Zero { .. } => 0,
+            PatchOp::Fill { len, .. } => *len,
+        })
+        .fold(0, u64::saturating_add)
+}
+
+/// Read a patch-from base file from the target, bounded by the blob limit.
+fn read_base(root: &rootdir::RootDir, base: &str, limits: &PatchLimits) -> Result<Vec<u8>> {
+    let file = root.open_file(&paths::decode_relative_path(base)?)?;
+    let mut data = Vec::new();
+    file.take(limits.max_blob_len.saturating_add(1)).read_to_end(&mut data)?;
+    if data.len() as u64 > limits.max_blob_len {
+        return Err(PatchError::Format(format!(
+            "Base file {:?} exceeds limit of {} bytes",
+            base, limits.max_blob_len
+        )));
+    }
+    Ok(data)
+}
+
+/// Account `len` more output bytes against `limits.max_total_output`.
+fn add_output(total: &AtomicU64, len: u64, limits: &PatchLimits) -> Result<()> {
+    let before = total.fetch_add(len, Ordering::Relaxed);
+    if before.saturating_add(len) > limits.max_total_output {
+        return Err(PatchError::Format(format!(
+            "Patch output exceeds limit of {} bytes",
+            limits.max_total_output
+        )));
+    }
     Ok(())
 }
 
+/// Temporary sibling path used for atomic writes (`<name>.tmp`).
+fn temp_path_for(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Read the manifest of a patch file, binary or JSON, without applying it.
+pub fn read_manifest(patch_path: &Path, limits: &PatchLimits) -> Result<types::Manifest> {
+    let patch_file = BufReader::new(File::open(patch_path)?);
+    patch::PatchReader::new(patch_file, limits)?.read_manifest()
+}
+
+/// Stream the manifest entries of a patch file one at a time.
+pub fn manifest_entries(
+    patch_path: &Path,
+    limits: &PatchLimits,
+) -> Result<manifest::EntryReader<std::io::Take<BufReader<File>>>> {
+    let patch_file = BufReader::new(File::open(patch_path)?);
+    let reader = patch::PatchReader::new(patch_file, limits)?;
+    reader.entries(BufReader::new(File::open(patch_path)?))
+}
+
 /// Serialize a patch object to a writer (streaming-friendly).
 pub fn write_patch<W: Write>(writer: W, p: &Patch) -> Result<()> {
     patch::write_patch(writer, p)
@@ -176,3 +803,606 @@ pub fn read_patch<R: Read>(reader: R) -> Result<Patch> {
     patch::read_patch(reader)
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::{Manifest, ManifestEntry};
+
+    fn file(path: &str, ops: Vec<PatchOp>) -> ManifestEntry {
+        ManifestEntry {
+            path: path.to_string(),
+            entry_type: "file".to_string(),
+            mode: 0o644,
+            mtime: 0,
+            sha256: None,
+            renamed_from: None,
+            ops,
+        }
+    }
+
+    fn add(data_offset: u64, data_length: u64) -> PatchOp {
+        PatchOp::Add {
+            data_offset,
+            data_length,
+            compressed: true,
+            compression: Some("zstd".to_string()),
+            zstd_level: Some(3),
+            blob_offset: 0,
+            base: None,
+            xor: None,
+        }
+    }
+
+    /// Write a patch with one zstd blob per payload; `entries` gets the blob
+    /// offsets.
+    fn write_patch_file(
+        path: &Path,
+        payloads: &[&[u8]],
+        entries: impl FnOnce(&[u64]) -> Vec<ManifestEntry>,
+    ) {
+        let mut writer = patch::PatchWriter::new(File::create(path).unwrap()).unwrap();
+        let offsets: Vec<u64> = payloads
+            .iter()
+            .map(|data| {
+                let payload = compress::compress(data, 3).unwrap();
+                writer.append_add_blob(&payload, &codec::Zstd, 3).unwrap()
+            })
+            .collect();
+        let mut manifest = Manifest::new();
+        manifest.entries = entries(&offsets);
+        writer.finish(&manifest).unwrap();
+    }
+
+    /// Apply the patch at `patch` to an empty target with `limits`.
+    fn apply_with(patch: &Path, limits: PatchLimits) -> Result<()> {
+        let target = tempfile::tempdir().unwrap();
+        let opts = ApplyPatchOptions {
+            limits,
+            ..Default::default()
+        };
+        apply_patch(target.path(), patch, &opts)
+    }
+
+    fn is_format<T>(result: Result<T>) -> bool {
+        matches!(result, Err(PatchError::Format(_)))
+    }
+
+    /// Write `files` below `root`, creating their directories.
+    fn write_tree(root: &Path, files: &[(&str, &[u8])]) {
+        for (path, data) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+    }
+
+    /// Assert every file below `expected` has the same contents below
+    /// `actual`.
+    fn assert_tree_matches(actual: &Path, expected: &Path) {
+        for rel in diff::list_files_sorted(expected).unwrap() {
+            let (got, want) = (fs::read(actual.join(&rel)), fs::read(expected.join(&rel)).unwrap());
+            assert!(got.is_ok_and(|got| got == want), "{} differs", rel.display());
+        }
+    }
+
+    /// Make a patch turning `src` into `dst`, apply it to `src` with `apply`,
+    /// check the result matches `dst`, and return the patch manifest.
+    fn make_and_apply(src: &Path, dst: &Path, make: &MakePatchOptions, apply: &ApplyPatchOptions) -> Manifest {
+        let out = tempfile::tempdir().unwrap();
+        let patch = out.path().join("p.patch");
+        make_patch(src, dst, &patch, make).unwrap();
+        apply_patch(src, &patch, apply).unwrap();
+        assert_tree_matches(src, dst);
+        read_manifest(&patch, &PatchLimits::default()).unwrap()
+    }
+
+    #[test]
+    fn within_limits_applies() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = dir.path().join("p.patch");
+        write_patch_file(&patch, &[b"hello"], |o| {
+            vec![file("a", vec![add(o[0], 5), PatchOp::Zero { len: 3 }])]
+        });
+        let target = tempfile::tempdir().unwrap();
+        apply_patch(target.path(), &patch, &ApplyPatchOptions::default()).unwrap();
+        assert_eq!(fs::read(target.path().join("a")).unwrap(), b"hello\0\0\0");
+    }
+
+    #[test]
+    fn manifest_length_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = dir.path().join("p.patch");
+        // Compresses to a few hundred bytes, decodes to far more
+        let ops = vec![PatchOp::Zero { len: 1 }; 50_000];
+        write_patch_file(&patch, &[], |_| vec![file("a", ops)]);
+
+        let declared = PatchLimits {
+            max_manifest_len: 16,
+            ..Default::default()
+        };
+        assert!(is_format(apply_with(&patch, declared)));
+        let decoded = PatchLimits {
+            max_manifest_len: 4096,
+            ..Default::default()
+        };
+        assert!(is_format(apply_with(&patch, decoded)));
+    }
+
+    #[test]
+    fn entry_count_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = dir.path().join("p.patch");
+        write_patch_file(&patch, &[], |_| {
+            (0..3)
+                .map(|i| file(&format!("f{}", i), Vec::new()))
+                .collect()
+        });
+        let limits = PatchLimits {
+            max_entries: 2,
+            ..Default::default()
+        };
+        assert!(is_format(apply_with(&patch, limits)));
+    }
+
+    #[test]
+    fn blob_size_limit_stops_zstd_bomb() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = dir.path().join("p.patch");
+        let bomb = vec![0u8; 64 << 20];
+        write_patch_file(&patch, &[&bomb], |o| vec![file("a", vec![add(o[0], 1)])]);
+        let limits = PatchLimits {
+            max_blob_len: 1 << 20,
+            ..Default::default()
+        };
+        assert!(is_format(apply_with(&patch, limits.clone())));
+
+        // An ADD declaring more than the limit is refused before decoding
+        write_patch_file(&patch, &[b"x"], |o| {
+            vec![file("a", vec![add(o[0], 2 << 20)])]
+        });
+        assert!(is_format(apply_with(&patch, limits)));
+    }
+
+    #[test]
+    fn total_output_limit_counts_zero_and_fill() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = dir.path().join("p.patch");
+        let limits = PatchLimits {
+            max_total_output: 1 << 20,
+            ..Default::default()
+        };
+        let huge = 1u64 << 50;
+        let cases = [
+            vec![file("a", vec![PatchOp::Zero { len: huge }])],
+            vec![file(
+                "a",
+                vec![PatchOp::Fill {
+                    pattern: vec![7],
+                    len: huge,
+                }],
+            )],
+            // Each file fits, together they do not
+            vec![
+                file("a", vec![PatchOp::Zero { len: 600 << 10 }]),
+                file(
+                    "b",
+                    vec![PatchOp::Fill {
+                        pattern: vec![1, 2],
+                        len: 600 << 10,
+                    }],
+                ),
+            ],
+        ];
+        for entries in cases {
+            write_patch_file(&patch, &[], |_| entries);
+            assert!(is_format(apply_with(&patch, limits.clone())));
+        }
+    }
+
+    #[test]
+    fn path_length_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = dir.path().join("p.patch");
+        let limits = PatchLimits {
+            max_path_len: 16,
+            ..Default::default()
+        };
+        let long = "a".repeat(100);
+        let copy = PatchOp::Copy {
+            src: long.clone(),
+            block_index: 0,
+            len: 1,
+        };
+        for entries in [vec![file(&long, Vec::new())], vec![file("a", vec![copy])]] {
+            write_patch_file(&patch, &[], |_| entries);
+            assert!(is_format(apply_with(&patch, limits.clone())));
+        }
+    }
+
+    /// `len` bytes of xorshift noise.
+    fn noise(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    /// Diff `data` against an empty source, check the patch rebuilds it, and
+    /// return its ops: (data offset, length, blob offset) for an ADD, `None`
+    /// for a ZERO.
+    fn diff_adds(data: &[u8]) -> Vec<Option<(u64, u64, u64)>> {
+        let (src, dst, out) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        fs::write(dst.path().join("f"), data).unwrap();
+        let patch = out.path().join("p.patch");
+        make_patch(src.path(), dst.path(), &patch, &MakePatchOptions::default()).unwrap();
+        apply_patch(src.path(), &patch, &ApplyPatchOptions::default()).unwrap();
+        assert!(fs::read(src.path().join("f")).unwrap() == data);
+
+        let manifest = read_manifest(&patch, &PatchLimits::default()).unwrap();
+        manifest.entries[0]
+            .ops
+            .iter()
+            .map(|op| match op {
+                PatchOp::Add {
+                    data_offset,
+                    data_length,
+                    blob_offset,
+                    ..
+                } => Some((*data_offset, *data_length, *blob_offset)),
+                PatchOp::Zero { .. } => None,
+                other => panic!("unexpected op {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn consecutive_new_blocks_extend_one_add() {
+        let block = MakePatchOptions::default().block_size as u64;
+        let adds = diff_adds(&noise(3 * block as usize, 1));
+        assert!(matches!(adds[..], [Some((_, len, 0))] if len == 3 * block));
+    }
+
+    #[test]
+    fn add_runs_end_at_other_ops() {
+        let block = MakePatchOptions::default().block_size;
+        let mut data = noise(2 * block, 2);
+        data.resize(3 * block, 0);
+        data.extend(noise(block, 3));
+        let adds = diff_adds(&data);
+        let [Some((first, first_len, 0)), None, Some((second, second_len, 0))] = adds[..] else {
+            panic!("unexpected ops {:?}", adds);
+        };
+        assert_ne!(first, second);
+        assert_eq!((first_len, second_len), (2 * block as u64, block as u64));
+    }
+
+    #[test]
+    fn add_runs_split_at_max_add_run() {
+        let block = MakePatchOptions::default().block_size;
+        let adds = diff_adds(&noise(MAX_ADD_RUN + 2 * block, 4));
+        let [Some((first, first_len, 0)), Some((second, second_len, 0))] = adds[..] else {
+            panic!("unexpected ops {:?}", adds);
+        };
+        assert_ne!(first, second);
+        assert_eq!((first_len, second_len), (MAX_ADD_RUN as u64, 2 * block as u64));
+    }
+
+    #[test]
+    fn copy_output_must_read_earlier_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let patch = dir.path().join("p.patch");
+        let copy_output = |path: &str| PatchOp::CopyOutput {
+            path: path.to_string(),
+            offset: 0,
+            len: 3,
+        };
+        let fill = || PatchOp::Fill {
+            pattern: b"xyz".to_vec(),
+            len: 3,
+        };
+
+        for atomic in [true, false] {
+            let opts = ApplyPatchOptions {
+                atomic,
+                ..Default::default()
+            };
+            let target = tempfile::tempdir().unwrap();
+            fs::write(target.path().join("b"), b"old").unwrap();
+
+            // "a" would read whatever "b" holds before it is rebuilt
+            write_patch_file(&patch, &[], |_| vec![file("a", vec![copy_output("b")]), file("b", vec![fill()])]);
+            assert!(is_format(apply_patch(target.path(), &patch, &opts)));
+            assert!(!target.path().join("a").exists());
+            assert_eq!(fs::read(target.path().join("b")).unwrap(), b"old");
+
+            write_patch_file(&patch, &[], |_| vec![file("b", vec![fill()]), file("a", vec![copy_output("b")])]);
+            apply_patch(target.path(), &patch, &opts).unwrap();
+            assert_eq!(fs::read(target.path().join("a")).unwrap(), b"xyz");
+        }
+    }
+
+    #[test]
+    fn patch_streamed_to_a_plain_writer_applies() {
+        let (src, dst) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let old = noise(40_000, 5);
+        let mut new = old.clone();
+        new[10_000..12_000].fill(1);
+        new.extend(noise(5000, 6));
+        write_tree(src.path(), &[("a", &old)]);
+        write_tree(dst.path(), &[("a", &new), ("dir/b", b"new file")]);
+
+        // A Vec cannot seek back, so the manifest must go in the trailer
+        let mut bytes = Vec::new();
+        make_patch_to_writer(src.path(), dst.path(), &mut bytes, &MakePatchOptions::default()).unwrap();
+        assert!(bytes.ends_with(b"PATCHEND"));
+        let out = tempfile::tempdir().unwrap();
+        let patch = out.path().join("p.patch");
+        fs::write(&patch, &bytes).unwrap();
+
+        apply_patch(src.path(), &patch, &ApplyPatchOptions::default()).unwrap();
+        assert_tree_matches(src.path(), dst.path());
+        let whole = read_patch(&bytes[..]).unwrap();
+        assert_eq!(whole.manifest.entries.len(), 3);
+    }
+
+    #[test]
+    fn patch_from_reads_the_base_before_replacing_it() {
+        let old = noise(64 << 10, 7);
+        // Every block changes, but only slightly: all ADD, against the base
+        let mut new = old.clone();
+        for i in (0..new.len()).step_by(64) {
+            new[i] ^= 0x5a;
+        }
+        let make = MakePatchOptions {
+            patch_from: true,
+            ..Default::default()
+        };
+        for atomic in [true, false] {
+            let (src, dst) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+            write_tree(src.path(), &[("a", &old)]);
+            write_tree(dst.path(), &[("a", &new)]);
+            let apply = ApplyPatchOptions {
+                atomic,
+                ..Default::default()
+            };
+            let manifest = make_and_apply(src.path(), dst.path(), &make, &apply);
+            let ops = &manifest.entries[0].ops;
+            assert!(!ops.is_empty());
+            assert!(ops.iter().all(|op| matches!(op, PatchOp::Add { base: Some(base), .. } if base == "a")));
+        }
+    }
+    #[test]
+    fn dictionary_is_written_only_with_enough_samples() {
+        const WORDS: [&str; 8] = ["patch", "block", "forge", "manifest", "entry", "source", "delta", "blob"];
+        let make = MakePatchOptions {
+            dictionary_size: 4096,
+            ..Default::default()
+        };
+
+        // Many files of the same vocabulary: plenty to train on
+        let (src, dst) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        for i in 0..64 {
+            let text: Vec<u8> = noise(2048, i + 1)
+                .iter()
+                .flat_map(|&b| [WORDS[b as usize % WORDS.len()].as_bytes(), b" "].concat())
+                .collect();
+            write_tree(dst.path(), &[(&format!("f{}", i), &text)]);
+        }
+        let manifest = make_and_apply(src.path(), dst.path(), &make, &ApplyPatchOptions::default());
+        assert!(manifest.dictionary_offset.is_some());
+
+        let (src, dst) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        write_tree(dst.path(), &[("a", b"too little to train on")]);
+        let manifest = make_and_apply(src.path(), dst.path(), &make, &ApplyPatchOptions::default());
+        assert!(manifest.dictionary_offset.is_none());
+    }
+
+    #[test]
+    fn patch_bytes_do_not_depend_on_thread_count() {
+        let (src, dst) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        // Over 4 MiB of files of mixed sizes: several batches on one thread,
+        // many blobs per batch on eight
+        for i in 0..48u64 {
+            let len = (4 + i as usize % 29) * 4096 + i as usize * 37;
+            let mut new = noise(len, i + 1);
+            if i % 2 == 0 {
+                new[..len / 2].fill(b'a' + (i % 26) as u8);
+            }
+            if i % 3 == 0 {
+                let mut old = new.clone();
+                old[len / 3..len / 3 + 4096].fill(0);
+                write_tree(src.path(), &[(&format!("d{}/f{}", i % 4, i), &old)]);
+            }
+            write_tree(dst.path(), &[(&format!("d{}/f{}", i % 4, i), &new)]);
+        }
+
+        for compression_mode in [CompressionMode::Blocks, CompressionMode::Solid, CompressionMode::Seekable] {
+            let make = |threads| {
+                let opts = MakePatchOptions {
+                    threads,
+                    compression_mode,
+                    solid_group_size: 256 << 10,
+                    seekable_frame_size: 64 << 10,
+                    ..Default::default()
+                };
+                let mut bytes = Vec::new();
+                make_patch_to_writer(src.path(), dst.path(), &mut bytes, &opts).unwrap();
+                bytes
+            };
+            assert!(make(1) == make(8), "{:?} patches differ", compression_mode);
+        }
+    }
+
+    #[test]
+    fn parallel_apply_reads_sources_before_replacing_them() {
+        let block = 4096;
+        let blocks: Vec<Vec<u8>> = (0..8).map(|i| noise(6 * block, 10 + i)).collect();
+        let mut edited = blocks[0].clone();
+        edited[block..block + 100].fill(9);
+        let swap: Vec<(&str, &[u8])> = vec![("a", &blocks[0]), ("b", &blocks[1])];
+        let swapped: Vec<(&str, &[u8])> = vec![("a", &blocks[1]), ("b", &blocks[0])];
+        // Each file takes the next one's contents
+        let names = ["c0", "c1", "c2", "c3", "c4", "c5"];
+        let chain: Vec<(&str, &[u8])> = names.iter().zip(&blocks).map(|(n, d)| (*n, &d[..])).collect();
+        let shifted: Vec<(&str, &[u8])> = names.iter().zip(&blocks[1..]).map(|(n, d)| (*n, &d[..])).collect();
+        let renamed: Vec<(&str, &[u8])> = vec![("b", &edited)];
+
+        for (old, new) in [(&swap, &swapped), (&chain, &shifted), (&swap, &renamed)] {
+            // One byte of budget is less than any file needs
+            for max_inflight_bytes in [1 << 30, 1] {
+                let (src, dst) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+                write_tree(src.path(), old);
+                write_tree(dst.path(), new);
+                let apply = ApplyPatchOptions {
+                    threads: 4,
+                    max_inflight_bytes,
+                    ..Default::default()
+                };
+                let manifest = make_and_apply(src.path(), dst.path(), &MakePatchOptions::default(), &apply);
+                let reads_other = |e: &ManifestEntry| {
+                    e.ops.iter().any(|op| matches!(op, PatchOp::Copy { src, .. } if *src != e.path))
+                };
+                assert!(manifest.entries.iter().any(reads_other));
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_blocks_share_an_add_blob_without_output_copies() {
+        let block = 4096;
+        let (a, b) = (noise(block, 20), noise(block, 21));
+        let (src, dst) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        write_tree(dst.path(), &[("f", &[&a[..], &b, &a, &a].concat()), ("g", &[&b[..], &a].concat())]);
+        let make = MakePatchOptions {
+            output_copies: false,
+            ..Default::default()
+        };
+        let manifest = make_and_apply(src.path(), dst.path(), &make, &ApplyPatchOptions::default());
+
+        let adds: Vec<(u64, u64, u64)> = manifest
+            .entries
+            .iter()
+            .flat_map(|e| &e.ops)
+            .map(|op| match op {
+                PatchOp::Add {
+                    data_offset,
+                    data_length,
+                    blob_offset,
+                    ..
+                } => (*data_offset, *data_length, *blob_offset),
+                other => panic!("unexpected op {:?}", other),
+            })
+            .collect();
+        // A and B are stored once; every later copy reads them back
+        let block = block as u64;
+        assert!(adds.iter().all(|&(offset, _, _)| offset == adds[0].0));
+        let reads: Vec<(u64, u64)> = adds.iter().map(|&(_, len, at)| (len, at)).collect();
+        assert_eq!(reads, [(2 * block, 0), (block, 0), (block, 0), (block, block), (block, 0)]);
+    }
+
+    #[test]
+    fn padding_becomes_one_fill() {
+        let (src, dst) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        // Firmware-style image: data, then 0xFF padding up to the next part
+        let image = [noise(3 * 4096, 40), vec![0xff; 40 * 4096], noise(4096, 41)].concat();
+        write_tree(dst.path(), &[("image.bin", &image)]);
+        let manifest = make_and_apply(src.path(), dst.path(), &MakePatchOptions::default(), &ApplyPatchOptions::default());
+        let fills: Vec<_> = manifest.entries[0].ops.iter().filter(|op| matches!(op, PatchOp::Fill { .. })).collect();
+        assert!(matches!(fills[..], [PatchOp::Fill { pattern, len }] if *pattern == [0xff] && *len == 40 * 4096));
+    }
+
+    #[test]
+    fn edited_block_applies_as_xor_delta() {
+        let (src, dst) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let old = noise(4 * 4096, 50);
+        let mut new = old.clone();
+        new[2 * 4096 + 100..2 * 4096 + 300].fill(0x42);
+        write_tree(src.path(), &[("a", &old)]);
+        write_tree(dst.path(), &[("a", &new)]);
+
+        let manifest = make_and_apply(src.path(), dst.path(), &MakePatchOptions::default(), &ApplyPatchOptions::default());
+        let xors: Vec<_> = manifest.entries[0]
+            .ops
+            .iter()
+            .filter_map(|op| match op {
+                PatchOp::Add { xor: Some(xor), .. } => Some((xor.src.as_str(), xor.block_index)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(xors, [("a", 2)]);
+    }
+
+    #[test]
+    fn renamed_file_reads_its_pair() {
+        let blocks: Vec<Vec<u8>> = (0..4).map(|i| noise(4096, 60 + i)).collect();
+        let old = blocks.concat();
+        let mut new = old.clone();
+        new[3 * 4096 + 100..3 * 4096 + 300].fill(0x42);
+        // The decoy comes first by path and holds two of the blocks too
+        let decoy = [&blocks[1][..], &blocks[3]].concat();
+
+        let sources = |detect_renames| {
+            let (src, dst) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+            write_tree(src.path(), &[("a/decoy.bin", &decoy), ("old/report.txt", &old)]);
+            write_tree(dst.path(), &[("a/decoy.bin", &decoy), ("new/report.txt", &new)]);
+            let make = MakePatchOptions {
+                detect_renames,
+                ..Default::default()
+            };
+            let manifest = make_and_apply(src.path(), dst.path(), &make, &ApplyPatchOptions::default());
+            let entry = manifest.entries.into_iter().find(|e| e.path == "new/report.txt").unwrap();
+            let sources: Vec<String> = entry
+                .ops
+                .iter()
+                .map(|op| match op {
+                    PatchOp::Copy { src, .. } => src.clone(),
+                    PatchOp::Add { xor: Some(xor), .. } => xor.src.clone(),
+                    other => panic!("unexpected op {:?}", other),
+                })
+                .collect();
+            (entry.renamed_from, sources)
+        };
+
+        let (renamed_from, paired) = sources(true);
+        assert_eq!(renamed_from.as_deref(), Some("old/report.txt"));
+        assert!(paired.len() >= 2 && paired.iter().all(|src| src == "old/report.txt"));
+        let (renamed_from, unpaired) = sources(false);
+        assert!(renamed_from.is_none());
+        assert!(unpaired.iter().any(|src| src == "a/decoy.bin"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn holes_stay_unallocated() {
+        use std::os::unix::fs::{FileExt, MetadataExt};
+
+        let (src, dst) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (head, tail) = (noise(4096, 30), noise(4096, 31));
+        let hole = 64u64 << 20;
+        let sparse = File::create(dst.path().join("sparse")).unwrap();
+        sparse.write_all_at(&head, 0).unwrap();
+        sparse.write_all_at(&tail, 4096 + hole).unwrap();
+        drop(sparse);
+
+        let manifest = make_and_apply(src.path(), dst.path(), &MakePatchOptions::default(), &ApplyPatchOptions::default());
+        let ops = &manifest.entries[0].ops;
+        assert!(ops.iter().any(|op| matches!(op, PatchOp::Zero { len } if *len >= hole)));
+        let added: u64 = ops
+            .iter()
+            .map(|op| match op {
+                PatchOp::Add { data_length, .. } => *data_length,
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(added, 2 * 4096);
+
+        let applied = fs::metadata(src.path().join("sparse")).unwrap();
+        assert_eq!(applied.len(), 2 * 4096 + hole);
+        assert!(applied.blocks() * 512 < hole);
+    }
+}
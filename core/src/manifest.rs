@@ -0,0 +1,730 @@
+//! Binary manifest encoding.
+//!
+//! Manifests are stored as `PFMB`, a format version byte and a zstd frame
+//! holding varints. Every string (paths, COPY sources, codec names, entry
+//! types, checksums) is written once to a string table and referenced by
+//! index, so a path repeated by thousands of COPY ops costs a few bytes per
+//! use. The string table comes first, so entries can then be decoded one
+//! at a time by an [`EntryReader`]. Readers still accept JSON manifests
+//! from older patches.
+
+use crate::types::*;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+
+/// Magic at the start of a binary manifest.
+pub const MAGIC: &[u8; 4] = b"PFMB";
+/// Current binary manifest format version.
+pub const FORMAT_VERSION: u8 = 1;
+
+const ZSTD_LEVEL: i32 = 9;
+const OP_COPY: u8 = 0;
+const OP_ADD: u8 = 1;
+const OP_COPY_OUTPUT: u8 = 2;
+const OP_ZERO: u8 = 3;
+const OP_FILL: u8 = 4;
+const FLAG_COMPRESSED: u8 = 1;
+const FLAG_XOR: u8 = 2;
+
+/// Encode `manifest` in the binary format.
+pub fn encode(manifest: &Manifest) -> Result<Vec<u8>> {
+    let mut strings = StringTable::default();
+    let mut body = Vec::new();
+
+    put_varint(&mut body, manifest.version as u64);
+    body.push(match manifest.compression_mode {
+        CompressionMode::Blocks => 0,
+        CompressionMode::Solid => 1,
+        CompressionMode::Seekable => 2,
+    });
+    put_varint(&mut body, manifest.dictionary_offset.map_or(0, |o| o + 1));
+
+    put_varint(&mut body, manifest.entries.len() as u64);
+    for entry in &manifest.entries {
+        put_varint(&mut body, strings.index(&entry.path));
+        put_varint(&mut body, strings.index(&entry.entry_type));
+        put_varint(&mut body, entry.mode as u64);
+        put_varint(&mut body, entry.mtime);
+        put_varint(&mut body, strings.optional(entry.sha256.as_deref()));
+        put_varint(&mut body, strings.optional(entry.renamed_from.as_deref()));
+
+        put_varint(&mut body, entry.ops.len() as u64);
+        for op in &entry.ops {
+            match op {
+                PatchOp::Copy {
+                    src,
+                    block_index,
+                    len,
+                } => {
+                    body.push(OP_COPY);
+                    put_varint(&mut body, strings.index(src));
+                    put_varint(&mut body, *block_index);
+                    put_varint(&mut body, *len as u64);
+                }
+                PatchOp::Add {
+                    data_offset,
+                    data_length,
+                    compressed,
+                    compression,
+                    zstd_level,
+                    blob_offset,
+                    base,
+                    xor,
+                } => {
+                    body.push(OP_ADD);
+                    put_varint(&mut body, *data_offset);
+                    put_varint(&mut body, *data_length);
+                    put_varint(&mut body, *blob_offset);
+                    let mut flags = if *compressed { FLAG_COMPRESSED } else { 0 };
+                    if xor.is_some() {
+                        flags |= FLAG_XOR;
+                    }
+                    body.push(flags);
+                    put_varint(&mut body, strings.optional(compression.as_deref()));
+                    put_varint(&mut body, zstd_level.map_or(0, |level| zigzag(level) + 1));
+                    put_varint(&mut body, strings.optional(base.as_deref()));
+                    if let Some(xor) = xor {
+                        put_varint(&mut body, strings.index(&xor.src));
+                        put_varint(&mut body, xor.block_index);
+                    }
+                }
+                PatchOp::CopyOutput { path, offset, len } => {
+                    body.push(OP_COPY_OUTPUT);
+                    put_varint(&mut body, strings.index(path));
+                    put_varint(&mut body, *offset);
+                    put_varint(&mut body, *len);
+                }
+                PatchOp::Zero { len } => {
+                    body.push(OP_ZERO);
+                    put_varint(&mut body, *len);
+                }
+                PatchOp::Fill { pattern, len } => {
+                    body.push(OP_FILL);
+                    put_varint(&mut body, pattern.len() as u64);
+                    body.extend_from_slice(pattern);
+                    put_varint(&mut body, *len);
+                }
+            }
+        }
+    }
+
+    // The string table goes first so readers can resolve indices in one pass
+    let mut raw = Vec::with_capacity(body.len() + strings.bytes);
+    put_varint(&mut raw, strings.list.len() as u64);
+    for s in &strings.list {
+        put_varint(&mut raw, s.len() as u64);
+        raw.extend_from_slice(s.as_bytes());
+    }
+    raw.extend_from_slice(&body);
+
+    let mut out = Vec::with_capacity(raw.len() / 4 + MAGIC.len() + 1);
+    out.extend_from_slice(MAGIC);
+    out.push(FORMAT_VERSION);
+    out.extend_from_slice(&crate::compress::compress(&raw, ZSTD_LEVEL)?);
+    Ok(out)
+}
+
+/// Reads a manifest, binary or JSON, one entry at a time.
+///
+/// The binary form is decoded as a stream: only the string table and the
+/// current entry are held in memory. A JSON manifest (older patches) is
+/// parsed whole first. Entries are checked against [`PatchLimits`] as they
+/// are read; iteration stops at the first error.
+pub struct EntryReader<R: Read> {
+    header: Manifest,
+    source: Source<R>,
+    limits: PatchLimits,
+}
+
+enum Source<R: Read> {
+    Binary {
+        input: Input<R>,
+        strings: Vec<String>,
+        remaining: usize, // Entries not read yet
+    },
+    Json(std::vec::IntoIter<ManifestEntry>),
+    Done,
+}
+
+impl<R: Read> EntryReader<R> {
+    /// Read the manifest fields before the entries. `reader` must end where
+    /// the manifest does.
+    pub fn new(mut reader: R, limits: &PatchLimits) -> Result<Self> {
+        let mut magic = Vec::with_capacity(MAGIC.len() + 1);
+        (&mut reader).take(MAGIC.len() as u64 + 1).read_to_end(&mut magic)?;
+
+        if !magic.starts_with(MAGIC) {
+            let mut json = magic;
+            reader.read_to_end(&mut json)?;
+            let json = String::from_utf8(json)
+                .map_err(|e| PatchError::Format(format!("Invalid UTF-8 in manifest: {}", e)))?;
+            let mut header = Manifest::from_json(&json)?;
+            check_entry_count(header.entries.len() as u64, limits)?;
+            let entries = std::mem::take(&mut header.entries);
+            return Ok(Self {
+                header,
+                source: Source::Json(entries.into_iter()),
+                limits: limits.clone(),
+            });
+        }
+
+        match magic.get(MAGIC.len()) {
+            Some(&FORMAT_VERSION) => {}
+            Some(version) => {
+                return Err(PatchError::Unsupported(format!(
+                    "Binary manifest format version {}",
+                    version
+                )))
+            }
+            None => return Err(PatchError::Format("Binary manifest truncated".to_string())),
+        }
+        let decoder = zstd::stream::read::Decoder::new(reader)
+            .map_err(|e| PatchError::Compression(format!("Zstd decompression failed: {}", e)))?;
+        let mut input = Input {
+            reader: BufReader::new(decoder.take(limits.max_manifest_len.saturating_add(1))),
+            read: 0,
+            max_len: limits.max_manifest_len,
+        };
+
+        let count = input.varint()?;
+        let mut strings = Vec::new();
+        for _ in 0..count {
+            let len = input.varint()?;
+            if len > limits.max_path_len as u64 {
+                return Err(PatchError::Format(format!(
+                    "Manifest string of {} bytes exceeds limit {}",
+                    len, limits.max_path_len
+                )));
+            }
+            let bytes = input.bytes(len as usize)?;
+            let s = String::from_utf8(bytes)
+                .map_err(|e| PatchError::Format(format!("Invalid UTF-8 in manifest: {}", e)))?;
+            strings.push(s);
+        }
+
+        let version = u32::try_from(input.varint()?)
+            .map_err(|_| PatchError::Format("Manifest version out of range".to_string()))?;
+        let compression_mode = match input.byte()? {
+            0 => CompressionMode::Blocks,
+            1 => CompressionMode::Solid,
+            2 => CompressionMode::Seekable,
+            mode => return Err(PatchError::Format(format!("Unknown compression mode {}", mode))),
+        };
+        let dictionary_offset = input.varint()?.checked_sub(1);
+        let remaining = input.varint()?;
+        check_entry_count(remaining, limits)?;
+
+        Ok(Self {
+            header: Manifest {
+                version,
+                compression_mode,
+                dictionary_offset,
+                entries: Vec::new(),
+            },
+            source: Source::Binary {
+                input,
+                strings,
+                remaining: remaining as usize,
+            },
+            limits: limits.clone(),
+        })
+    }
+
+    /// The manifest fields other than `entries` (which is empty).
+    pub fn header(&self) -> &Manifest {
+        &self.header
+    }
+
+    /// Read the remaining entries into a whole [`Manifest`].
+    pub fn into_manifest(mut self) -> Result<Manifest> {
+        let mut entries = Vec::new();
+        for entry in &mut self {
+            entries.push(entry?);
+        }
+        Ok(Manifest {
+            entries,
+            ..self.header
+        })
+    }
+
+    /// Write the manifest as JSON, exactly as [`Manifest::to_json`] would,
+    /// one entry at a time.
+    pub fn write_json<W: Write>(mut self, mut out: W) -> Result<()> {
+        // `entries` is the last field, so the header's JSON ends with `[]}`
+        let header = self.header.to_json()?;
+        let open = header.strip_suffix("[]}").expect("entries serialize last");
+        out.write_all(open.as_bytes())?;
+        out.write_all(b"[")?;
+        for (i, entry) in (&mut self).enumerate() {
+            if i > 0 {
+                out.write_all(b",")?;
+            }
+            serde_json::to_writer(&mut out, &entry?)?;
+        }
+        out.write_all(b"]}")?;
+        Ok(())
+    }
+
+    fn read_entry(&mut self) -> Result<Option<ManifestEntry>> {
+        let (input, strings, remaining) = match &mut self.source {
+            Source::Binary {
+                input,
+                strings,
+                remaining,
+            } => (input, strings, remaining),
+            Source::Json(entries) => return Ok(entries.next()),
+            Source::Done => return Ok(None),
+        };
+        if *remaining == 0 {
+            if !input.at_end()? {
+                return Err(PatchError::Format("Trailing bytes after manifest".to_string()));
+            }
+            return Ok(None);
+        }
+        *remaining -= 1;
+
+        let string = |index: u64| -> Result<String> {
+            strings
+                .get(index as usize)
+                .cloned()
+                .ok_or_else(|| PatchError::Format(format!("Manifest string index {} out of range", index)))
+        };
+        let optional = |index: u64| index.checked_sub(1).map(string).transpose();
+
+        let path = string(input.varint()?)?;
+        let entry_type = string(input.varint()?)?;
+        let mode = u32::try_from(input.varint()?)
+            .map_err(|_| PatchError::Format("Entry mode out of range".to_string()))?;
+        let mtime = input.varint()?;
+        let sha256 = optional(input.varint()?)?;
+        let renamed_from = optional(input.varint()?)?;
+
+        let op_count = input.varint()?;
+        let mut ops = Vec::new();
+        for _ in 0..op_count {
+            let op = match input.byte()? {
+                OP_COPY => PatchOp::Copy {
+                    src: string(input.varint()?)?,
+                    block_index: input.varint()?,
+                    len: usize::try_from(input.varint()?)
+                        .map_err(|_| PatchError::Format("COPY length out of range".to_string()))?,
+                },
+                OP_ADD => {
+                    let data_offset = input.varint()?;
+                    let data_length = input.varint()?;
+                    let blob_offset = input.varint()?;
+                    let flags = input.byte()?;
+                    let compression = optional(input.varint()?)?;
+                    let zstd_level = match input.varint()?.checked_sub(1) {
+                        Some(level) => Some(unzigzag(level)?),
+                        None => None,
+                    };
+                    let base = optional(input.varint()?)?;
+                    let xor = if flags & FLAG_XOR != 0 {
+                        Some(XorSource {
+                            src: string(input.varint()?)?,
+                            block_index: input.varint()?,
+                        })
+                    } else {
+                        None
+                    };
+                    PatchOp::Add {
+                        data_offset,
+                        data_length,
+                        compressed: flags & FLAG_COMPRESSED != 0,
+                        compression,
+                        zstd_level,
+                        blob_offset,
+                        base,
+                        xor,
+                    }
+                }
+                OP_COPY_OUTPUT => PatchOp::CopyOutput {
+                    path: string(input.varint()?)?,
+                    offset: input.varint()?,
+                    len: input.varint()?,
+                },
+                OP_ZERO => PatchOp::Zero { len: input.varint()? },
+                OP_FILL => {
+                    let pattern_len = input.varint()?;
+                    if pattern_len == 0 || pattern_len > MAX_FILL_PATTERN as u64 {
+                        return Err(PatchError::Format(format!("FILL pattern of {} bytes", pattern_len)));
+                    }
+                    PatchOp::Fill {
+                        pattern: input.bytes(pattern_len as usize)?,
+                        len: input.varint()?,
+                    }
+                }
+                tag => return Err(PatchError::Format(format!("Unknown manifest op {}", tag))),
+            };
+            ops.push(op);
+        }
+
+        Ok(Some(ManifestEntry {
+            path,
+            entry_type,
+            mode,
+            mtime,
+            sha256,
+            renamed_from,
+            ops,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for EntryReader<R> {
+    type Item = Result<ManifestEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self
+            .read_entry()
+            .and_then(|entry| match entry {
+                Some(entry) => check_entry_limits(&entry, &self.limits).map(|_| Some(entry)),
+                None => Ok(None),
+            })
+            .transpose();
+        if !matches!(entry, Some(Ok(_))) {
+            self.source = Source::Done;
+        }
+        entry
+    }
+}
+
+/// Reject a manifest with more entries than `limits` allow.
+fn check_entry_count(count: u64, limits: &PatchLimits) -> Result<()> {
+    if count > limits.max_entries as u64 {
+        return Err(PatchError::Format(format!(
+            "Manifest has {} entries, limit is {}",
+            count, limits.max_entries
+        )));
+    }
+    Ok(())
+}
+
+/// Check the path lengths and ADD sizes of one entry against `limits`.
+pub fn check_entry_limits(entry: &ManifestEntry, limits: &PatchLimits) -> Result<()> {
+    let check_path = |path: &str| {
+        if path.len() > limits.max_path_len {
+            return Err(PatchError::Format(format!(
+                "Path of {} bytes exceeds limit {}",
+                path.len(),
+                limits.max_path_len
+            )));
+        }
+        Ok(())
+    };
+
+    check_path(&entry.path)?;
+    if let Some(renamed_from) = &entry.renamed_from {
+        check_path(renamed_from)?;
+    }
+    for op in &entry.ops {
+        match op {
+            PatchOp::Copy { src, .. } | PatchOp::CopyOutput { path: src, .. } => check_path(src)?,
+            PatchOp::Add {
+                data_length, base, xor, ..
+            } => {
+                if let Some(base) = base {
+                    check_path(base)?;
+                }
+                if let Some(xor) = xor {
+                    check_path(&xor.src)?;
+                }
+                if *data_length > limits.max_blob_len {
+                    return Err(PatchError::Format(format!(
+                        "ADD length {} exceeds limit {}",
+                        data_length, limits.max_blob_len
+                    )));
+                }
+            }
+            PatchOp::Zero { .. } => {}
+            PatchOp::Fill { pattern, .. } => {
+                if pattern.is_empty() || pattern.len() > MAX_FILL_PATTERN {
+                    return Err(PatchError::Format(format!("FILL pattern of {} bytes", pattern.len())));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Strings interned in first-use order.
+#[derive(Default)]
+struct StringTable<'a> {
+    list: Vec<&'a str>,
+    indices: HashMap<&'a str, u64>,
+    bytes: usize,
+}
+
+impl<'a> StringTable<'a> {
+    fn index(&mut self, s: &'a str) -> u64 {
+        if let Some(&index) = self.indices.get(s) {
+            return index;
+        }
+        let index = self.list.len() as u64;
+        self.list.push(s);
+        self.indices.insert(s, index);
+        self.bytes += s.len() + 1;
+        index
+    }
+
+    /// Index + 1, or 0 for `None`.
+    fn optional(&mut self, s: Option<&'a str>) -> u64 {
+        s.map_or(0, |s| self.index(s) + 1)
+    }
+}
+
+type ZstdReader<R> = zstd::stream::read::Decoder<'static, BufReader<R>>;
+
+/// Decompressed manifest bytes, capped at `max_len`.
+struct Input<R: Read> {
+    reader: BufReader<std::io::Take<ZstdReader<R>>>,
+    read: u64,
+    max_len: u64,
+}
+
+impl<R: Read> Input<R> {
+    fn byte(&mut self) -> Result<u8> {
+        let mut byte = [0u8];
+        self.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0u8; len];
+        self.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(PatchError::Format("Manifest varint too long".to_string()))
+    }
+
+    fn at_end(&mut self) -> Result<bool> {
+        Ok(self.fill()?.is_empty())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Err(self.eof_error()),
+            Err(e) => return Err(PatchError::Compression(format!("Zstd decompression failed: {}", e))),
+        }
+        self.read += buf.len() as u64;
+        if self.read > self.max_len {
+            return Err(self.eof_error());
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self) -> Result<&[u8]> {
+        self.reader
+            .fill_buf()
+            .map_err(|e| PatchError::Compression(format!("Zstd decompression failed: {}", e)))
+    }
+
+    fn eof_error(&self) -> PatchError {
+        if self.read > self.max_len || self.reader.get_ref().limit() == 0 {
+            PatchError::Format(format!("Manifest decompresses beyond limit {}", self.max_len))
+        } else {
+            PatchError::Format("Binary manifest truncated".to_string())
+        }
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn zigzag(value: i32) -> u64 {
+    ((value << 1) ^ (value >> 31)) as u32 as u64
+}
+
+fn unzigzag(value: u64) -> Result<i32> {
+    let value = u32::try_from(value)
+        .map_err(|_| PatchError::Format("Compression level out of range".to_string()))?;
+    Ok((value >> 1) as i32 ^ -((value & 1) as i32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Result<Manifest> {
+        EntryReader::new(bytes, &PatchLimits::default())?.into_manifest()
+    }
+
+    /// A binary manifest around the raw (uncompressed) body.
+    fn binary(raw: &[u8]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(FORMAT_VERSION);
+        out.extend(crate::compress::compress(raw, ZSTD_LEVEL).unwrap());
+        out
+    }
+
+    /// A manifest using every op and every optional field.
+    fn sample() -> Manifest {
+        let add = |compressed: bool, zstd_level: Option<i32>, base: Option<&str>, xor: Option<XorSource>| PatchOp::Add {
+            data_offset: 1 << 40,
+            data_length: 4096,
+            compressed,
+            compression: compressed.then(|| "zstd".to_string()),
+            zstd_level,
+            blob_offset: 8192,
+            base: base.map(str::to_string),
+            xor,
+        };
+        let xor = XorSource {
+            src: "old/b64:AAEC".to_string(),
+            block_index: 7,
+        };
+        Manifest {
+            version: 1,
+            compression_mode: CompressionMode::Seekable,
+            dictionary_offset: Some(0),
+            entries: vec![
+                ManifestEntry {
+                    path: "dir".to_string(),
+                    entry_type: "dir".to_string(),
+                    mode: 0o755,
+                    mtime: 0,
+                    sha256: None,
+                    renamed_from: None,
+                    ops: Vec::new(),
+                },
+                ManifestEntry {
+                    path: "dir/new".to_string(),
+                    entry_type: "file".to_string(),
+                    mode: 0o100644,
+                    mtime: u64::MAX,
+                    sha256: Some("ab".repeat(32)),
+                    renamed_from: Some("dir/old".to_string()),
+                    ops: vec![
+                        PatchOp::Copy {
+                            src: "dir/old".to_string(),
+                            block_index: u64::MAX,
+                            len: 4096,
+                        },
+                        add(true, Some(-7), None, None),
+                        add(true, Some(i32::MIN), Some("dir/old"), None),
+                        add(false, None, None, Some(xor)),
+                        PatchOp::CopyOutput {
+                            path: "dir/new".to_string(),
+                            offset: 4096,
+                            len: 12288,
+                        },
+                        PatchOp::Zero { len: 1 << 50 },
+                        PatchOp::Fill {
+                            pattern: vec![0xff; MAX_FILL_PATTERN],
+                            len: 100,
+                        },
+                    ],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let manifest = sample();
+        let decoded = decode(&encode(&manifest).unwrap()).unwrap();
+        assert_eq!(decoded.to_json().unwrap(), manifest.to_json().unwrap());
+
+        let mut empty = Manifest::new();
+        empty.dictionary_offset = Some(1 << 33);
+        let decoded = decode(&encode(&empty).unwrap()).unwrap();
+        assert_eq!(decoded.to_json().unwrap(), empty.to_json().unwrap());
+    }
+
+    #[test]
+    fn zigzag_round_trip() {
+        for level in [0, 1, -1, 22, -131072, i32::MAX, i32::MIN] {
+            assert_eq!(unzigzag(zigzag(level)).unwrap(), level);
+        }
+        assert!(unzigzag(u64::from(u32::MAX) + 1).is_err());
+    }
+
+    #[test]
+    fn json_fallback() {
+        let manifest = sample();
+        let json = manifest.to_json().unwrap();
+        let decoded = decode(json.as_bytes()).unwrap();
+        assert_eq!(decoded.to_json().unwrap(), json);
+
+        let mut streamed = Vec::new();
+        EntryReader::new(&encode(&manifest).unwrap()[..], &PatchLimits::default())
+            .unwrap()
+            .write_json(&mut streamed)
+            .unwrap();
+        assert_eq!(String::from_utf8(streamed).unwrap(), json);
+    }
+
+    #[test]
+    fn entry_reader_streams_every_entry_in_order() {
+        let mut manifest = Manifest::new();
+        let entry = &sample().entries[1];
+        for i in 0..5000 {
+            manifest.entries.push(ManifestEntry {
+                path: format!("dir{}/f{}", i % 7, i),
+                ops: entry.ops[..i % entry.ops.len()].to_vec(),
+                ..entry.clone()
+            });
+        }
+        let expected: Vec<String> = manifest.entries.iter().map(|e| serde_json::to_string(e).unwrap()).collect();
+
+        for bytes in [encode(&manifest).unwrap(), manifest.to_json().unwrap().into_bytes()] {
+            let reader = EntryReader::new(&bytes[..], &PatchLimits::default()).unwrap();
+            let read: Vec<String> = reader.map(|e| serde_json::to_string(&e.unwrap()).unwrap()).collect();
+            assert_eq!(read.len(), expected.len());
+            assert!(read == expected);
+        }
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let bytes = encode(&sample()).unwrap();
+        for len in 0..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err(), "decoded {} of {} bytes", len, bytes.len());
+        }
+    }
+
+    #[test]
+    fn unknown_version_byte() {
+        let mut bytes = encode(&sample()).unwrap();
+        for version in [0, FORMAT_VERSION + 1, u8::MAX] {
+            bytes[MAGIC.len()] = version;
+            assert!(matches!(decode(&bytes), Err(PatchError::Unsupported(_))));
+        }
+    }
+
+    #[test]
+    fn string_index_out_of_range() {
+        // String table ["a"], header, one entry
+        let head = [1, 1, b'a', 1, 0, 0, 1];
+        let entries: [&[u8]; 5] = [
+            &[1, 0, 0o44, 0, 0, 0, 0],                   // Path
+            &[0, 1, 0o44, 0, 0, 0, 0],                   // Entry type
+            &[0, 0, 0o44, 0, 2, 0, 0],                   // SHA-256
+            &[0, 0, 0o44, 0, 0, 2, 0],                   // Renamed from
+            &[0, 0, 0o44, 0, 0, 0, 1, OP_COPY, 9, 0, 1], // COPY source
+        ];
+        for entry in entries {
+            let raw = [&head[..], entry].concat();
+            assert!(matches!(decode(&binary(&raw)), Err(PatchError::Format(_))));
+        }
+        let valid = [&head[..], &[0, 0, 0o44, 0, 0, 0, 1, OP_COPY, 0, 0, 1]].concat();
+        assert!(decode(&binary(&valid)).is_ok());
+    }
+}
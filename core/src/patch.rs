@@ -1,41 +1,504 @@
 //! Patch serialization and deserialization.
 
+use crate::codec::{self, Codec};
+use crate::compress;
+use crate::manifest::EntryReader;
 use crate::types::*;
-use std::io::{Read, Write};
+use std::collections::VecDeque;
+use std::io::{Read, Seek, SeekFrom, Take, Write};
+use std::sync::Arc;
+use zstd::dict::DecoderDictionary;
 
 const PATCH_HEADER_SIZE: usize = 20;
+const BLOB_HEADER_SIZE: usize = 13;
 
-/// Write a patch to a writer.
-pub fn write_patch<W: Write>(mut writer: W, patch: &Patch) -> Result<()> {
-    // Write header
-    let manifest_json = patch.manifest.to_json()?;
-    let manifest_bytes = manifest_json.as_bytes();
-    let header = PatchHeader::new(manifest_bytes.len() as u64);
-    writer.write_all(&header.to_bytes())?;
+/// Streaming patch writer.
+///
+/// Writes the header up front, streams ADD blobs straight to the output and
+/// appends the manifest and [`PatchTrailer`] in [`PatchWriter::finish`], so
+/// neither the data section nor the output needs to be seekable.
+pub struct PatchWriter<W: Write> {
+    writer: W,
+    data_len: u64,
+}
+
+impl<W: Write> PatchWriter<W> {
+    /// Start a patch by writing its header.
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(&PatchHeader::streaming().to_bytes())?;
+        Ok(Self {
+            writer,
+            data_len: 0,
+        })
+    }
+
+    /// Append an ADD blob encoded with `codec` and return its offset in the
+    /// data section.
+    pub fn append_add_blob(&mut self, payload: &[u8], codec: &dyn Codec, level: i32) -> Result<u64> {
+        self.append_blob(blob_header(payload, codec, level), payload)
+    }
+
+    /// Append a seekable blob produced by `compress::compress_seekable`.
+    pub fn append_seekable_blob(&mut self, payload: &[u8], codec: &dyn Codec, level: i32) -> Result<u64> {
+        let mut header = blob_header(payload, codec, level);
+        header.seekable = true;
+        self.append_blob(header, payload)
+    }
+
+    /// Append data that was judged incompressible, stored raw.
+    pub fn append_skipped_blob(&mut self, data: &[u8]) -> Result<u64> {
+        let mut header = blob_header(data, &codec::Store, 0);
+        header.skipped = true;
+        self.append_blob(header, data)
+    }
+
+    fn append_blob(&mut self, header: BlobHeader, payload: &[u8]) -> Result<u64> {
+        let offset = self.data_len;
+        let header = header.to_bytes();
+
+        self.writer.write_all(&header)?;
+        self.writer.write_all(payload)?;
+        self.data_len += (header.len() + payload.len()) as u64;
+
+        Ok(offset)
+    }
+
+    /// Append raw data section bytes (already framed as blobs).
+    pub fn append_raw(&mut self, data: &[u8]) -> Result<()> {
+        self.writer.write_all(data)?;
+        self.data_len += data.len() as u64;
+        Ok(())
+    }
+
+    /// Write the manifest and trailer, returning the underlying writer.
+    pub fn finish(mut self, manifest: &Manifest) -> Result<W> {
+        let manifest_bytes = crate::manifest::encode(manifest)?;
+        self.writer.write_all(&manifest_bytes)?;
+        let trailer = PatchTrailer::new(self.data_len, manifest_bytes.len() as u64);
+        self.writer.write_all(&trailer.to_bytes())?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Random-access patch reader.
+///
+/// Parses only the header (and trailer for version 2 patches) up front; the
+/// manifest and individual ADD blobs are read on demand by seeking, so
+/// memory use stays bounded regardless of the patch size.
+pub struct PatchReader<R: Read + Seek> {
+    reader: R,
+    limits: PatchLimits,
+    manifest_start: u64,
+    manifest_len: u64,
+    data_start: u64,
+    data_len: u64,
+    cache: VecDeque<DecodedSpan>, // Most recently used first
+    frame_index: Option<(u64, Vec<SeekFrame>)>,
+    dictionary: Option<Arc<DecoderDictionary<'static>>>,
+}
+
+impl<R: Read + Seek> PatchReader<R> {
+    /// Locate the manifest and data section of a patch.
+    pub fn new(mut reader: R, limits: &PatchLimits) -> Result<Self> {
+        let mut header_buf = [0u8; PATCH_HEADER_SIZE];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut header_buf)?;
+        let (header, header_size) = PatchHeader::from_bytes(&header_buf)?;
+        let header_size = header_size as u64;
+        let total_len = reader.seek(SeekFrom::End(0))?;
+
+        let (manifest_start, manifest_len, data_start, data_len) =
+            if header.version == PATCH_VERSION_STREAMING {
+                let trailer_start = total_len
+                    .checked_sub(PatchTrailer::SIZE as u64)
+                    .filter(|&t| t >= header_size)
+                    .ok_or_else(|| PatchError::Format("Patch truncated before trailer".to_string()))?;
+                let mut trailer_buf = [0u8; PatchTrailer::SIZE];
+                reader.seek(SeekFrom::Start(trailer_start))?;
+                reader.read_exact(&mut trailer_buf)?;
+                let trailer = PatchTrailer::from_bytes(&trailer_buf)?;
+
+                if trailer.data_len.checked_add(trailer.manifest_len) != Some(trailer_start - header_size) {
+                    return Err(PatchError::Format(
+                        "Trailer lengths do not match patch size".to_string(),
+                    ));
+                }
+                (
+                    header_size + trailer.data_len,
+                    trailer.manifest_len,
+                    header_size,
+                    trailer.data_len,
+                )
+            } else {
+                let data_start = header_size
+                    .checked_add(header.manifest_len)
+                    .filter(|&d| d <= total_len)
+                    .ok_or_else(|| PatchError::Format("Manifest extends past end of patch".to_string()))?;
+                (header_size, header.manifest_len, data_start, total_len - data_start)
+            };
+
+        check_manifest_len(manifest_len, limits)?;
+
+        Ok(Self {
+            reader,
+            limits: limits.clone(),
+            manifest_start,
+            manifest_len,
+            data_start,
+            data_len,
+            cache: VecDeque::new(),
+            frame_index: None,
+            dictionary: None,
+        })
+    }
+
+    /// Read and validate the whole manifest, loading the patch dictionary
+    /// if it has one.
+    pub fn read_manifest(&mut self) -> Result<Manifest> {
+        self.reader.seek(SeekFrom::Start(self.manifest_start))?;
+        let manifest_reader = (&mut self.reader).take(self.manifest_len);
+        let manifest = EntryReader::new(manifest_reader, &self.limits)?.into_manifest()?;
+        self.load_dictionary(&manifest)?;
+        Ok(manifest)
+    }
+
+    /// Read the manifest fields other than its entries, loading the patch
+    /// dictionary. Entries are then streamed with [`PatchReader::entries`].
+    pub fn read_manifest_header(&mut self) -> Result<Manifest> {
+        self.reader.seek(SeekFrom::Start(self.manifest_start))?;
+        let manifest_reader = (&mut self.reader).take(self.manifest_len);
+        let header = EntryReader::new(manifest_reader, &self.limits)?.header().clone();
+        self.load_dictionary(&header)?;
+        Ok(header)
+    }
+
+    /// Stream the manifest entries through another handle to the patch.
+    pub fn entries<R2: Read + Seek>(&self, mut reader: R2) -> Result<EntryReader<Take<R2>>> {
+        reader.seek(SeekFrom::Start(self.manifest_start))?;
+        EntryReader::new(reader.take(self.manifest_len), &self.limits)
+    }
+
+    fn load_dictionary(&mut self, manifest: &Manifest) -> Result<()> {
+        self.dictionary = None;
+        self.cache.clear();
+        if let Some(offset) = manifest.dictionary_offset {
+            let raw = self.read_add_blob(offset, self.limits.max_blob_len)?;
+            self.dictionary = Some(Arc::new(DecoderDictionary::copy(&raw)));
+        }
+        Ok(())
+    }
 
-    // Write manifest
-    writer.write_all(manifest_bytes)?;
+    /// A reader over another handle to the same patch, sharing the located
+    /// sections and loaded dictionary but with its own decode cache. Used to
+    /// read blobs from several threads.
+    pub fn reopen<R2: Read + Seek>(&self, reader: R2) -> PatchReader<R2> {
+        PatchReader {
+            reader,
+            limits: self.limits.clone(),
+            manifest_start: self.manifest_start,
+            manifest_len: self.manifest_len,
+            data_start: self.data_start,
+            data_len: self.data_len,
+            cache: VecDeque::new(),
+            frame_index: None,
+            dictionary: self.dictionary.clone(),
+        }
+    }
+
+    /// Length of the data section in bytes.
+    pub fn data_len(&self) -> u64 {
+        self.data_len
+    }
+
+    /// Read the ADD blob at `offset` in the data section, decoding at most
+    /// `max_len` bytes.
+    pub fn read_add_blob(&mut self, offset: u64, max_len: u64) -> Result<Vec<u8>> {
+        let header = self.read_blob_header(offset)?;
+        let payload = (&mut self.reader).take(header.payload_len);
+        let reference = ZstdRef::from_parts(None, self.dictionary.as_deref());
+        decode_payload(payload, &header, max_len, reference)
+    }
+
+    /// Read `len` bytes starting `blob_offset` bytes into the decoded
+    /// content of the ADD blob at `offset`. `prefix` is the base file for
+    /// blobs compressed with a reference prefix; other blobs are decoded
+    /// with the patch dictionary, if any.
+    ///
+    /// The last few decoded blobs (or, for seekable blobs, frames) are
+    /// kept, so consecutive ranges of a solid group are decoded only once,
+    /// as are groups that deduplicated ADDs alternate between.
+    pub fn read_add_range(
+        &mut self,
+        offset: u64,
+        blob_offset: u64,
+        len: u64,
+        prefix: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        let end = blob_offset
+            .checked_add(len)
+            .filter(|_| len <= self.limits.max_blob_len)
+            .ok_or_else(|| PatchError::Format(format!("ADD range of {} bytes is too large", len)))?;
+
+        let mut out = Vec::with_capacity(len as usize);
+        let mut pos = blob_offset;
+        while pos < end {
+            let span = self.load_span(offset, pos, prefix)?;
+            let span_end = span.start + span.data.len() as u64;
+            let from = (pos - span.start) as usize;
+            let take = (end.min(span_end) - pos) as usize;
+            out.extend_from_slice(&span.data[from..from + take]);
+            pos += take as u64;
+        }
+        Ok(out)
+    }
+
+    /// The decoded span covering `pos` in the blob at `offset`, from the
+    /// cache or decoded into it.
+    fn load_span(&mut self, offset: u64, pos: u64, prefix: Option<&[u8]>) -> Result<&DecodedSpan> {
+        if let Some(idx) = self.cache.iter().position(|span| span.covers(offset, pos)) {
+            let span = self.cache.remove(idx).expect("index found above");
+            self.cache.push_front(span);
+        } else {
+            // Evict first, so at most CACHED_SPANS are held while decoding
+            self.cache.truncate(CACHED_SPANS - 1);
+            let header = self.read_blob_header(offset)?;
+            let span = if header.seekable {
+                self.decode_frame(offset, &header, pos)?
+            } else {
+                let payload = (&mut self.reader).take(header.payload_len);
+                let reference = ZstdRef::from_parts(prefix, self.dictionary.as_deref());
+                DecodedSpan {
+                    blob: offset,
+                    start: 0,
+                    data: decode_payload(payload, &header, self.limits.max_blob_len, reference)?,
+                }
+            };
+            if !span.covers(offset, pos) {
+                return Err(PatchError::Format(format!(
+                    "ADD range at {} exceeds blob at {}",
+                    pos, offset
+                )));
+            }
+            self.cache.push_front(span);
+        }
+        Ok(&self.cache[0])
+    }
+
+    /// Decode the frame of a seekable blob that contains `pos`.
+    fn decode_frame(&mut self, offset: u64, header: &BlobHeader, pos: u64) -> Result<DecodedSpan> {
+        if !matches!(&self.frame_index, Some((blob, _)) if *blob == offset) {
+            let mut payload = (&mut self.reader).take(header.payload_len);
+            let frames = read_frame_index(&mut payload, header.payload_len, self.limits.max_blob_len)?;
+            self.frame_index = Some((offset, frames));
+        }
+        let frames = &self.frame_index.as_ref().expect("index loaded above").1;
+
+        let idx = frames.partition_point(|f| f.raw_start + f.raw_len <= pos);
+        let frame = frames.get(idx).copied().ok_or_else(|| {
+            PatchError::Format(format!("ADD range at {} exceeds blob at {}", pos, offset))
+        })?;
+
+        let payload_start = self.data_start + offset + BLOB_HEADER_SIZE as u64;
+        self.reader
+            .seek(SeekFrom::Start(payload_start + frame.payload_offset))?;
+        let reference = ZstdRef::from_parts(None, self.dictionary.as_deref());
+        let data = decompress_from(
+            (&mut self.reader).take(frame.compressed_len),
+            frame.raw_len,
+            header.codec,
+            reference,
+        )?;
+        if data.len() as u64 != frame.raw_len {
+            return Err(PatchError::Format("Seekable frame length mismatch".to_string()));
+        }
+
+        Ok(DecodedSpan {
+            blob: offset,
+            start: frame.raw_start,
+            data,
+        })
+    }
+
+    /// Read and validate the blob header at `offset`, leaving the reader at
+    /// the start of its payload.
+    fn read_blob_header(&mut self, offset: u64) -> Result<BlobHeader> {
+        let header_end = offset
+            .checked_add(BLOB_HEADER_SIZE as u64)
+            .filter(|&end| end <= self.data_len)
+            .ok_or_else(|| PatchError::Format("ADD blob offset out of range".to_string()))?;
+
+        let mut header_buf = [0u8; BLOB_HEADER_SIZE];
+        self.reader.seek(SeekFrom::Start(self.data_start + offset))?;
+        self.reader.read_exact(&mut header_buf)?;
+        let (header, _) = BlobHeader::from_bytes(&header_buf)?;
+
+        if header_end
+            .checked_add(header.payload_len)
+            .is_none_or(|end| end > self.data_len)
+        {
+            return Err(PatchError::Format("ADD blob payload out of range".to_string()));
+        }
+        Ok(header)
+    }
+}
+
+/// What a zstd frame was compressed against.
+#[derive(Clone, Copy)]
+enum ZstdRef<'a> {
+    None,
+    Prefix(&'a [u8]),
+    Dictionary(&'a DecoderDictionary<'static>),
+}
+
+impl<'a> ZstdRef<'a> {
+    /// A patch-from prefix takes precedence over the patch dictionary.
+    fn from_parts(prefix: Option<&'a [u8]>, dictionary: Option<&'a DecoderDictionary<'static>>) -> Self {
+        match (prefix, dictionary) {
+            (Some(prefix), _) => Self::Prefix(prefix),
+            (None, Some(dictionary)) => Self::Dictionary(dictionary),
+            (None, None) => Self::None,
+        }
+    }
+}
+
+/// Decoded spans a [`PatchReader`] keeps.
+const CACHED_SPANS: usize = 4;
+
+/// Decoded bytes of a blob (or one frame of a seekable blob).
+struct DecodedSpan {
+    blob: u64,
+    start: u64,
+    data: Vec<u8>,
+}
+
+impl DecodedSpan {
+    fn covers(&self, blob: u64, pos: u64) -> bool {
+        self.blob == blob && pos >= self.start && pos < self.start + self.data.len() as u64
+    }
+}
+
+/// One frame of a seekable blob.
+#[derive(Debug, Clone, Copy)]
+struct SeekFrame {
+    payload_offset: u64, // From the start of the blob payload
+    compressed_len: u64,
+    raw_start: u64, // Offset in the decoded blob
+    raw_len: u64,
+}
 
-    // Write data section
-    writer.write_all(&patch.data)?;
+/// Parse the frame index at the start of a seekable blob payload.
+fn read_frame_index<R: Read>(payload: &mut R, payload_len: u64, max_frame_len: u64) -> Result<Vec<SeekFrame>> {
+    let mut count_buf = [0u8; 4];
+    payload.read_exact(&mut count_buf)?;
+    let count = u32::from_be_bytes(count_buf) as u64;
 
+    let index_len = 4 + count * 8;
+    if index_len > payload_len {
+        return Err(PatchError::Format("Seekable frame index out of range".to_string()));
+    }
+
+    let mut frames = Vec::with_capacity(count as usize);
+    let (mut payload_offset, mut raw_start) = (index_len, 0u64);
+    for _ in 0..count {
+        let mut entry = [0u8; 8];
+        payload.read_exact(&mut entry)?;
+        let compressed_len = u32::from_be_bytes(entry[0..4].try_into().unwrap()) as u64;
+        let raw_len = u32::from_be_bytes(entry[4..8].try_into().unwrap()) as u64;
+        if raw_len > max_frame_len {
+            return Err(PatchError::Format(format!(
+                "Seekable frame of {} bytes exceeds limit {}",
+                raw_len, max_frame_len
+            )));
+        }
+        frames.push(SeekFrame {
+            payload_offset,
+            compressed_len,
+            raw_start,
+            raw_len,
+        });
+        payload_offset += compressed_len;
+        raw_start += raw_len;
+    }
+
+    if payload_offset > payload_len {
+        return Err(PatchError::Format("Seekable frames out of range".to_string()));
+    }
+    Ok(frames)
+}
+
+/// Decode a whole blob payload, failing once the output exceeds `max_len`.
+fn decode_payload<R: Read>(
+    mut payload: R,
+    header: &BlobHeader,
+    max_len: u64,
+    reference: ZstdRef,
+) -> Result<Vec<u8>> {
+    if header.seekable {
+        // Frames follow the index back to back, so they can be read in order
+        let frames = read_frame_index(&mut payload, header.payload_len, max_len)?;
+        let mut out = Vec::new();
+        for frame in frames {
+            if out.len() as u64 + frame.raw_len > max_len {
+                return Err(PatchError::Format(format!(
+                    "ADD blob decompresses beyond limit {}",
+                    max_len
+                )));
+            }
+            let data = decompress_from((&mut payload).take(frame.compressed_len), frame.raw_len, header.codec, reference)?;
+            if data.len() as u64 != frame.raw_len {
+                return Err(PatchError::Format("Seekable frame length mismatch".to_string()));
+            }
+            out.extend_from_slice(&data);
+        }
+        Ok(out)
+    } else if header.compressed() {
+        decompress_from(payload, max_len, header.codec, reference)
+    } else if header.payload_len > max_len {
+        Err(PatchError::Format(format!(
+            "ADD blob of {} bytes exceeds limit {}",
+            header.payload_len, max_len
+        )))
+    } else {
+        let mut out = Vec::with_capacity(header.payload_len as usize);
+        payload.read_to_end(&mut out)?;
+        if out.len() as u64 != header.payload_len {
+            return Err(PatchError::Format("ADD blob payload truncated".to_string()));
+        }
+        Ok(out)
+    }
+}
+
+/// Write an in-memory patch to a writer.
+pub fn write_patch<W: Write>(writer: W, patch: &Patch) -> Result<()> {
+    let mut patch_writer = PatchWriter::new(writer)?;
+    patch_writer.append_raw(&patch.data)?;
+    patch_writer.finish(&patch.manifest)?;
     Ok(())
 }
 
-/// Read a patch from a reader.
-pub fn read_patch<R: Read>(mut reader: R) -> Result<Patch> {
+/// Read a patch from a reader using the default [`PatchLimits`].
+pub fn read_patch<R: Read>(reader: R) -> Result<Patch> {
+    read_patch_with_limits(reader, &PatchLimits::default())
+}
+
+/// Read a patch from a reader, rejecting anything beyond `limits`.
+pub fn read_patch_with_limits<R: Read>(mut reader: R, limits: &PatchLimits) -> Result<Patch> {
     // Read header
     let mut header_buf = [0u8; PATCH_HEADER_SIZE];
     reader.read_exact(&mut header_buf)?;
 
     let (header, _) = PatchHeader::from_bytes(&header_buf)?;
 
+    if header.version == PATCH_VERSION_STREAMING {
+        return read_streaming_body(reader, limits);
+    }
+
     // Read manifest
+    check_manifest_len(header.manifest_len, limits)?;
     let mut manifest_buf = vec![0u8; header.manifest_len as usize];
     reader.read_exact(&mut manifest_buf)?;
-    let manifest_json = String::from_utf8(manifest_buf)
-        .map_err(|e| PatchError::Format(format!("Invalid UTF-8 in manifest: {}", e)))?;
-    let manifest = Manifest::from_json(&manifest_json)?;
+    let manifest = parse_manifest(manifest_buf, limits)?;
 
     // Read data section
     let mut data = Vec::new();
@@ -44,61 +507,244 @@ pub fn read_patch<R: Read>(mut reader: R) -> Result<Patch> {
     Ok(Patch { manifest, data })
 }
 
+/// Read the body of a version 2 patch: data, manifest, trailer.
+fn read_streaming_body<R: Read>(mut reader: R, limits: &PatchLimits) -> Result<Patch> {
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body)?;
+
+    let trailer_start = body
+        .len()
+        .checked_sub(PatchTrailer::SIZE)
+        .ok_or_else(|| PatchError::Format("Patch truncated before trailer".to_string()))?;
+    let trailer = PatchTrailer::from_bytes(&body[trailer_start..])?;
+    check_manifest_len(trailer.manifest_len, limits)?;
+
+    if trailer.data_len.checked_add(trailer.manifest_len) != Some(trailer_start as u64) {
+        return Err(PatchError::Format(
+            "Trailer lengths do not match patch size".to_string(),
+        ));
+    }
+
+    let data_len = trailer.data_len as usize;
+    let manifest_buf = body[data_len..trailer_start].to_vec();
+    body.truncate(data_len);
+    let manifest = parse_manifest(manifest_buf, limits)?;
+
+    Ok(Patch {
+        manifest,
+        data: body,
+    })
+}
+
+fn check_manifest_len(manifest_len: u64, limits: &PatchLimits) -> Result<()> {
+    if manifest_len > limits.max_manifest_len {
+        return Err(PatchError::Format(format!(
+            "Manifest length {} exceeds limit {}",
+            manifest_len, limits.max_manifest_len
+        )));
+    }
+    Ok(())
+}
+
+fn parse_manifest(manifest_buf: Vec<u8>, limits: &PatchLimits) -> Result<Manifest> {
+    EntryReader::new(&manifest_buf[..], limits)?.into_manifest()
+}
+
 /// Append ADD blob to patch data section and return its offset.
 pub fn append_add_blob(
     patch: &mut Patch,
     payload: &[u8],
-    compressed: bool,
-    zstd_level: i32,
+    codec: &dyn Codec,
+    level: i32,
 ) -> Result<u64> {
     let offset = patch.data.len() as u64;
 
-    let header = BlobHeader {
-        compressed,
-        zstd_level: if compressed { zstd_level as u32 } else { 0 },
-        payload_len: payload.len() as u64,
-    };
-
-    patch.data.extend_from_slice(&header.to_bytes());
+    patch
+        .data
+        .extend_from_slice(&blob_header(payload, codec, level).to_bytes());
     patch.data.extend_from_slice(payload);
 
     Ok(offset)
 }
 
-/// Read ADD blob from patch data section.
-pub fn read_add_blob(patch: &Patch, offset: u64) -> Result<Vec<u8>> {
-    let offset = offset as usize;
-    if offset >= patch.data.len() {
-        return Err(PatchError::Format("ADD blob offset out of range".to_string()));
+fn blob_header(payload: &[u8], codec: &dyn Codec, level: i32) -> BlobHeader {
+    let compressed = codec.id() != codec::STORE_ID;
+    BlobHeader {
+        codec: codec.id(),
+        seekable: false,
+        skipped: false,
+        level: if compressed { level as u32 } else { 0 },
+        payload_len: payload.len() as u64,
     }
+}
 
-    let (header, header_size) = BlobHeader::from_bytes(&patch.data[offset..])?;
-    let payload_offset = offset + header_size;
-    let payload_end = payload_offset + header.payload_len as usize;
+/// Read ADD blob from patch data section, decoding at most `max_len` bytes.
+pub fn read_add_blob(patch: &Patch, offset: u64, max_len: u64) -> Result<Vec<u8>> {
+    let dictionary = match patch.manifest.dictionary_offset {
+        Some(dict_offset) => {
+            let (header, payload) = blob_payload(patch, dict_offset)?;
+            Some(DecoderDictionary::copy(&decode_payload(payload, &header, max_len, ZstdRef::None)?))
+        }
+        None => None,
+    };
+    let (header, payload) = blob_payload(patch, offset)?;
+    decode_payload(payload, &header, max_len, ZstdRef::from_parts(None, dictionary.as_ref()))
+}
 
-    if payload_end > patch.data.len() {
-        return Err(PatchError::Format("ADD blob payload out of range".to_string()));
-    }
+/// Locate the header and payload of the blob at `offset`.
+fn blob_payload(patch: &Patch, offset: u64) -> Result<(BlobHeader, &[u8])> {
+    let offset = usize::try_from(offset)
+        .ok()
+        .filter(|&o| o < patch.data.len())
+        .ok_or_else(|| PatchError::Format("ADD blob offset out of range".to_string()))?;
 
-    let payload = &patch.data[payload_offset..payload_end];
+    let (header, header_size) = BlobHeader::from_bytes(&patch.data[offset..])?;
+    let payload_offset = offset + header_size;
+    let payload_end = usize::try_from(header.payload_len)
+        .ok()
+        .and_then(|len| payload_offset.checked_add(len))
+        .filter(|&end| end <= patch.data.len())
+        .ok_or_else(|| PatchError::Format("ADD blob payload out of range".to_string()))?;
 
-    if header.compressed {
-        decompress_blob(payload)
-    } else {
-        Ok(payload.to_vec())
-    }
+    Ok((header, &patch.data[payload_offset..payload_end]))
 }
 
 /// Compress a blob with zstd.
 pub fn compress_blob(data: &[u8], level: i32) -> Result<Vec<u8>> {
-    zstd::encode_all(data, level as i32).map_err(|e| {
+    zstd::encode_all(data, level).map_err(|e| {
         PatchError::Compression(format!("Zstd compression failed: {}", e))
     })
 }
 
-/// Decompress a blob with zstd.
-pub fn decompress_blob(data: &[u8]) -> Result<Vec<u8>> {
-    zstd::decode_all(data).map_err(|e| {
-        PatchError::Compression(format!("Zstd decompression failed: {}", e))
-    })
+/// Decompress a blob with zstd, failing once the output exceeds `max_len`.
+pub fn decompress_blob(data: &[u8], max_len: u64) -> Result<Vec<u8>> {
+    decompress_from(data, max_len, codec::ZSTD_ID, ZstdRef::None)
+}
+
+/// Decompress a stream encoded with codec `codec_id` from a reader, failing
+/// once the output exceeds `max_len`. For zstd, `reference` must match what
+/// the stream was compressed against; other codecs ignore it.
+fn decompress_from<R: Read>(
+    mut reader: R,
+    max_len: u64,
+    codec_id: u8,
+    reference: ZstdRef,
+) -> Result<Vec<u8>> {
+    if codec_id != codec::ZSTD_ID {
+        return codec::by_id(codec_id)?.decompress(&mut reader, max_len);
+    }
+
+    let map_err = |e: std::io::Error| PatchError::Compression(format!("Zstd decompression failed: {}", e));
+    let reader = std::io::BufReader::new(reader);
+    let decoder = match reference {
+        ZstdRef::Prefix(prefix) => {
+            let mut decoder = zstd::stream::read::Decoder::with_ref_prefix(reader, prefix).map_err(map_err)?;
+            decoder
+                .window_log_max(compress::PATCH_FROM_MAX_WINDOW_LOG)
+                .map_err(map_err)?;
+            decoder
+        }
+        ZstdRef::Dictionary(dictionary) => {
+            zstd::stream::read::Decoder::with_prepared_dictionary(reader, dictionary).map_err(map_err)?
+        }
+        ZstdRef::None => zstd::stream::read::Decoder::with_buffer(reader).map_err(map_err)?,
+    };
+    codec::read_bounded(decoder, max_len, "Zstd")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn alternating_ranges_stay_cached() {
+        let mut writer = PatchWriter::new(Vec::new()).unwrap();
+        let blobs: Vec<Vec<u8>> = (0..CACHED_SPANS as u8 + 1).map(|i| vec![i; 1000]).collect();
+        let offsets: Vec<u64> = blobs
+            .iter()
+            .map(|blob| {
+                let payload = compress::compress(blob, 3).unwrap();
+                writer.append_add_blob(&payload, &codec::Zstd, 3).unwrap()
+            })
+            .collect();
+        let patch = writer.finish(&Manifest::new()).unwrap();
+        let mut reader = PatchReader::new(Cursor::new(patch), &PatchLimits::default()).unwrap();
+
+        for _ in 0..3 {
+            for (blob, &offset) in blobs.iter().zip(&offsets).take(2) {
+                assert_eq!(reader.read_add_range(offset, 10, 20, None).unwrap(), blob[10..30]);
+            }
+        }
+        assert_eq!(reader.cache.len(), 2);
+
+        // Every blob once: the least recently used is evicted
+        for (blob, &offset) in blobs.iter().zip(&offsets) {
+            assert_eq!(reader.read_add_range(offset, 0, 1000, None).unwrap(), *blob);
+        }
+        assert_eq!(reader.cache.len(), CACHED_SPANS);
+        assert!(reader.cache.iter().all(|span| span.blob != offsets[0]));
+        assert_eq!(reader.cache[0].blob, *offsets.last().unwrap());
+        assert!(reader.read_add_range(offsets[0], 990, 20, None).is_err());
+    }
+
+    /// A streamed patch with one blob and an empty manifest.
+    fn streamed() -> Vec<u8> {
+        let mut writer = PatchWriter::new(Vec::new()).unwrap();
+        let payload = compress::compress(b"blob", 3).unwrap();
+        writer.append_add_blob(&payload, &codec::Zstd, 3).unwrap();
+        writer.finish(&Manifest::new()).unwrap()
+    }
+
+    fn rejected(patch: Vec<u8>) -> bool {
+        let limits = PatchLimits::default();
+        let seeking = PatchReader::new(Cursor::new(patch.clone()), &limits);
+        matches!(seeking, Err(PatchError::Format(_)))
+            && matches!(read_patch_with_limits(&patch[..], &limits), Err(PatchError::Format(_)))
+    }
+
+    #[test]
+    fn streamed_layout_reads_back() {
+        let patch = streamed();
+        let (header, header_len) = PatchHeader::from_bytes(&patch).unwrap();
+        assert_eq!(header.version, PATCH_VERSION_STREAMING);
+        let trailer = PatchTrailer::from_bytes(&patch[patch.len() - PatchTrailer::SIZE..]).unwrap();
+        assert_eq!(
+            header_len as u64 + trailer.data_len + trailer.manifest_len + PatchTrailer::SIZE as u64,
+            patch.len() as u64
+        );
+
+        let mut reader = PatchReader::new(Cursor::new(patch.clone()), &PatchLimits::default()).unwrap();
+        assert!(reader.read_manifest().unwrap().entries.is_empty());
+        assert_eq!(reader.read_add_blob(0, 100).unwrap(), b"blob");
+        assert_eq!(read_patch(&patch[..]).unwrap().data.len() as u64, trailer.data_len);
+    }
+
+    #[test]
+    fn bad_trailer_magic() {
+        let mut patch = streamed();
+        let end = patch.len();
+        patch[end - 3..].copy_from_slice(b"BAD");
+        assert!(rejected(patch));
+    }
+
+    #[test]
+    fn inconsistent_trailer_lengths() {
+        let patch = streamed();
+        let trailer_at = patch.len() - PatchTrailer::SIZE;
+        let trailer = PatchTrailer::from_bytes(&patch[trailer_at..]).unwrap();
+        for (data_len, manifest_len) in [
+            (trailer.data_len + 1, trailer.manifest_len),
+            (trailer.data_len, trailer.manifest_len - 1),
+            (u64::MAX, trailer.manifest_len),
+            (0, 0),
+        ] {
+            let mut bad = patch[..trailer_at].to_vec();
+            bad.extend(PatchTrailer::new(data_len, manifest_len).to_bytes());
+            assert!(rejected(bad), "{} + {}", data_len, manifest_len);
+        }
+        // Too short to hold a trailer at all
+        let header_len = PatchHeader::streaming().to_bytes().len();
+        assert!(rejected(patch[..header_len + 4].to_vec()));
+    }
 }
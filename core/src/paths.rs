@@ -0,0 +1,299 @@
+//! Manifest path encoding.
+//!
+//! Manifest paths are stored as strings. Names that are valid UTF-8 are
+//! stored verbatim; anything else (or a name that happens to start with the
+//! escape prefix) is stored as `b64:` followed by the base64 of the raw
+//! `OsStr` bytes, so it round-trips exactly on Unix.
+
+use crate::types::{ManifestEntry, PatchError, PatchOp, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::path::{Component, Path, PathBuf};
+
+/// Prefix marking a base64-encoded raw path.
+pub const RAW_PATH_PREFIX: &str = "b64:";
+
+/// Encode a relative path for storage in the manifest.
+pub fn encode_path(path: &Path) -> Result<String> {
+    match path.to_str() {
+        Some(s) if !s.starts_with(RAW_PATH_PREFIX) => Ok(s.to_string()),
+        _ => Ok(format!("{}{}", RAW_PATH_PREFIX, STANDARD.encode(raw_bytes(path)?))),
+    }
+}
+
+/// Decode a manifest path back into a relative path.
+pub fn decode_path(encoded: &str) -> Result<PathBuf> {
+    match encoded.strip_prefix(RAW_PATH_PREFIX) {
+        Some(b64) => {
+            let bytes = STANDARD
+                .decode(b64)
+                .map_err(|e| PatchError::Format(format!("Invalid encoded path {:?}: {}", encoded, e)))?;
+            from_raw_bytes(bytes)
+        }
+        None => Ok(PathBuf::from(encoded)),
+    }
+}
+
+/// Decode an untrusted manifest path and make sure it stays below the root.
+///
+/// Absolute paths, drive and UNC prefixes, `..` components and empty paths
+/// are rejected; `.` components are dropped. Backslashes count as
+/// separators for this check on every platform, so a patch cannot escape
+/// the root wherever it is applied.
+pub fn decode_relative_path(encoded: &str) -> Result<PathBuf> {
+    let decoded = decode_path(encoded)?;
+    if escapes_on_windows(&decoded) {
+        return Err(PatchError::Format(format!("Path {:?} escapes the target root", encoded)));
+    }
+    let mut normalized = PathBuf::new();
+
+    for component in decoded.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(PatchError::Format(format!(
+                    "Path {:?} escapes the target root",
+                    encoded
+                )));
+            }
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        return Err(PatchError::Format(format!("Empty path {:?} in manifest", encoded)));
+    }
+
+    Ok(normalized)
+}
+
+/// Whether `path` read with Windows rules has a drive or UNC prefix, starts
+/// at the root, or holds a `..` component.
+fn escapes_on_windows(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    let bytes = path.as_bytes();
+    let drive = bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':';
+    drive || path.starts_with('\\') || path.split(['/', '\\']).any(|part| part == "..")
+}
+
+/// Check the path, rename source, COPY sources, ADD bases, XOR sources and
+/// COPY_OUTPUT paths of one manifest entry with [`decode_relative_path`].
+pub fn validate_entry(entry: &ManifestEntry) -> Result<()> {
+    decode_relative_path(&entry.path)?;
+    if let Some(renamed_from) = &entry.renamed_from {
+        decode_relative_path(renamed_from)?;
+    }
+    for op in &entry.ops {
+        match op {
+            PatchOp::Copy { src, .. } => {
+                decode_relative_path(src)?;
+            }
+            PatchOp::Add { base, xor, .. } => {
+                if let Some(base) = base {
+                    decode_relative_path(base)?;
+                }
+                if let Some(xor) = xor {
+                    decode_relative_path(&xor.src)?;
+                }
+            }
+            PatchOp::Zero { .. } | PatchOp::Fill { .. } => {}
+            PatchOp::CopyOutput { path, .. } => {
+                decode_relative_path(path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn raw_bytes(path: &Path) -> Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(path.as_os_str().as_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+fn raw_bytes(path: &Path) -> Result<Vec<u8>> {
+    path.to_str().map(|s| s.as_bytes().to_vec()).ok_or_else(|| {
+        PatchError::Unsupported(format!(
+            "Path {:?} is not valid Unicode and cannot be stored on this platform",
+            path
+        ))
+    })
+}
+
+#[cfg(unix)]
+fn from_raw_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+fn from_raw_bytes(bytes: Vec<u8>) -> Result<PathBuf> {
+    String::from_utf8(bytes).map(PathBuf::from).map_err(|e| {
+        PatchError::Unsupported(format!(
+            "Path bytes {:?} cannot be represented on this platform",
+            e.into_bytes()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Manifest, XorSource};
+
+    fn rejected(path: &str) -> bool {
+        matches!(decode_relative_path(path), Err(PatchError::Format(_)))
+    }
+
+    fn b64(raw: &[u8]) -> String {
+        format!("{}{}", RAW_PATH_PREFIX, STANDARD.encode(raw))
+    }
+
+    fn file_entry(path: &str, ops: Vec<PatchOp>) -> ManifestEntry {
+        ManifestEntry {
+            path: path.to_string(),
+            entry_type: "file".to_string(),
+            mode: 0o644,
+            mtime: 0,
+            sha256: None,
+            renamed_from: None,
+            ops,
+        }
+    }
+
+    fn add(base: Option<&str>, xor: Option<&str>) -> PatchOp {
+        PatchOp::Add {
+            data_offset: 0,
+            data_length: 1,
+            compressed: false,
+            compression: None,
+            zstd_level: None,
+            blob_offset: 0,
+            base: base.map(str::to_string),
+            xor: xor.map(|src| XorSource {
+                src: src.to_string(),
+                block_index: 0,
+            }),
+        }
+    }
+
+    #[test]
+    fn accepts_relative_paths() {
+        assert_eq!(decode_relative_path("a/b.txt").unwrap(), Path::new("a/b.txt"));
+        assert_eq!(decode_relative_path("./a/./b").unwrap(), Path::new("a/b"));
+        assert_eq!(decode_relative_path(&b64(b"dir/name")).unwrap(), Path::new("dir/name"));
+    }
+
+    #[test]
+    fn rejects_parent_components() {
+        for path in ["..", "../x", "a/../../x", "a/..", "a\\..\\..\\x"] {
+            assert!(rejected(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        for path in ["/etc/passwd", "//server/share/x", "\\windows\\system32"] {
+            assert!(rejected(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_drive_and_unc_prefixes() {
+        for path in ["C:\\x", "c:x", "C:/x", "\\\\server\\share\\x", "\\\\?\\C:\\x"] {
+            assert!(rejected(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_paths() {
+        for path in ["", ".", "./.", "b64:"] {
+            assert!(rejected(path), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_parent_components_inside_b64() {
+        for raw in [&b"../x"[..], b"a/../../x", b"/etc/passwd", b"..\\x", b"C:\\x"] {
+            assert!(rejected(&b64(raw)), "{:?}", raw);
+        }
+        assert!(rejected("b64:not base64!"));
+    }
+
+    #[test]
+    fn validate_entry_checks_every_path() {
+        assert!(validate_entry(&file_entry("ok", vec![add(Some("base"), Some("src"))])).is_ok());
+
+        let copy = PatchOp::Copy {
+            src: "../outside".to_string(),
+            block_index: 0,
+            len: 1,
+        };
+        let copy_output = PatchOp::CopyOutput {
+            path: "/abs".to_string(),
+            offset: 0,
+            len: 1,
+        };
+        let bad_ops = [copy, copy_output, add(Some("../base"), None), add(None, Some(&b64(b"../x")))];
+        for op in bad_ops {
+            let entry = file_entry("ok", vec![op.clone()]);
+            assert!(matches!(validate_entry(&entry), Err(PatchError::Format(_))), "{:?}", op);
+        }
+
+        assert!(validate_entry(&file_entry("../escape", Vec::new())).is_err());
+        let mut renamed = file_entry("ok", Vec::new());
+        renamed.renamed_from = Some("C:\\old".to_string());
+        assert!(validate_entry(&renamed).is_err());
+    }
+
+    /// `path` after a trip through [`encode_path`], a binary manifest and
+    /// [`decode_path`], with its encoded form.
+    fn round_trip(path: &Path) -> (PathBuf, String) {
+        let mut manifest = Manifest::new();
+        manifest.entries.push(file_entry(&encode_path(path).unwrap(), Vec::new()));
+        let bytes = crate::manifest::encode(&manifest).unwrap();
+        let limits = crate::types::PatchLimits::default();
+        let decoded = crate::manifest::EntryReader::new(&bytes[..], &limits).unwrap().into_manifest().unwrap();
+        let encoded = decoded.entries[0].path.clone();
+        (decode_path(&encoded).unwrap(), encoded)
+    }
+
+    #[test]
+    fn utf8_names_are_stored_verbatim() {
+        let (decoded, encoded) = round_trip(Path::new("dir/naïve.txt"));
+        assert_eq!(encoded, "dir/naïve.txt");
+        assert_eq!(decoded, Path::new("dir/naïve.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_round_trip() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"dir/bad\xffname\x80"));
+        let (decoded, encoded) = round_trip(path);
+        assert!(encoded.starts_with(RAW_PATH_PREFIX));
+        assert_eq!(decoded, path);
+        assert_eq!(decode_relative_path(&encoded).unwrap(), path);
+    }
+
+    #[test]
+    fn names_starting_with_the_prefix_are_escaped() {
+        let path = Path::new("b64:aGVsbG8=");
+        let (decoded, encoded) = round_trip(path);
+        assert_eq!(encoded, b64(b"b64:aGVsbG8="));
+        assert_eq!(decoded, path);
+        // Read as an escape, the name itself would decode to "hello"
+        assert_eq!(decode_path("b64:aGVsbG8=").unwrap(), Path::new("hello"));
+    }
+
+    #[test]
+    fn rejects_malformed_base64() {
+        for encoded in ["b64:not base64!", "b64:aGVsbG8", "b64:a===", "b64:@@@@"] {
+            assert!(matches!(decode_path(encoded), Err(PatchError::Format(_))), "{}", encoded);
+        }
+    }
+}
@@ -0,0 +1,448 @@
+//! Parallel compression of ADD blobs.
+//!
+//! `make_patch` reads ADD data in manifest order and hands it over as
+//! [`PendingBlob`]s. They are compressed in batches on a bounded worker pool
+//! and written back in submission order, so the patch is byte-for-byte the
+//! same whatever the thread count, and memory stays bounded by the batch
+//! size (roughly threads × `solid_group_size`).
+//!
+//! With a dictionary, blobs are held back while the first ADD blocks are
+//! sampled; the dictionary is then trained, written as the first blob, and
+//! the held blobs are compressed against it. Training ends early when the
+//! held blobs fill a batch, so it never holds more than one.
+//!
+//! New blocks that repeat an earlier ADD block are not buffered again: an
+//! [`AddIndex`] turns them into ADD ops that point into the first copy.
+
+use crate::codec::{self, Codec};
+use crate::compress;
+use crate::patch::PatchWriter;
+use crate::types::{MakePatchOptions, Manifest, PatchOp, Result};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// ADD data waiting to be compressed and written as one blob.
+pub(crate) struct PendingBlob {
+    data: Vec<u8>,
+    members: Vec<(usize, usize)>, // (entry index, op index) of each ADD
+    codec: &'static dyn Codec,
+    prefix: Option<Vec<u8>>, // Patch-from reference, zstd only
+    seekable: bool,
+    single_block: bool, // At most one block of data
+}
+
+impl PendingBlob {
+    fn compress(&self, opts: &MakePatchOptions, dictionary: Option<&compress::Dictionary>) -> Result<Option<Vec<u8>>> {
+        compress_unless_incompressible(self.codec, &self.data, opts, self.single_block, |data| {
+            match &self.prefix {
+                Some(prefix) => compress::compress_with_prefix(data, opts.zstd_level, prefix),
+                None if self.seekable => compress::compress_seekable(data, opts.seekable_frame_size, |frame| {
+                    compress_block(self.codec, frame, opts.zstd_level, dictionary)
+                }),
+                None => compress_block(self.codec, data, opts.zstd_level, dictionary),
+            }
+        })
+    }
+
+    /// Append the blob (raw if `payload` is `None`) and point its ops at it.
+    fn write<W: Write>(
+        &self,
+        payload: Option<Vec<u8>>,
+        writer: &mut PatchWriter<W>,
+        manifest: &mut Manifest,
+        opts: &MakePatchOptions,
+    ) -> Result<()> {
+        let skipped = payload.is_none();
+        let offset = match payload {
+            Some(payload) if self.seekable => writer.append_seekable_blob(&payload, self.codec, opts.zstd_level)?,
+            Some(payload) => writer.append_add_blob(&payload, self.codec, opts.zstd_level)?,
+            None => writer.append_skipped_blob(&self.data)?,
+        };
+
+        for &(entry_idx, op_idx) in &self.members {
+            if let PatchOp::Add {
+                data_offset,
+                compressed,
+                compression,
+                zstd_level,
+                ..
+            } = &mut manifest.entries[entry_idx].ops[op_idx]
+            {
+                *data_offset = offset;
+                if skipped {
+                    (*compressed, *compression, *zstd_level) = (false, None, None);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// ADD data buffered for one blob: a run of new blocks in blocks mode, or a
+/// solid, seekable or patch-from group.
+#[derive(Default)]
+pub(crate) struct AddGroup {
+    pub data: Vec<u8>,
+    pub members: Vec<(usize, usize)>, // (entry index, op index) of each ADD
+    pub codec: Option<&'static dyn Codec>, // Codec of the buffered files
+}
+
+impl AddGroup {
+    /// Take the buffered blocks as a blob, leaving the group empty. A
+    /// patch-from `prefix` always yields a single (non-seekable) blob.
+    pub fn take(&mut self, opts: &MakePatchOptions, prefix: Option<Vec<u8>>, seekable: bool) -> Option<PendingBlob> {
+        if self.members.is_empty() {
+            return None;
+        }
+        Some(PendingBlob {
+            single_block: self.data.len() <= opts.block_size,
+            data: std::mem::take(&mut self.data),
+            members: std::mem::take(&mut self.members),
+            codec: self.codec.unwrap_or(opts.codec),
+            seekable: seekable && prefix.is_none(),
+            prefix,
+        })
+    }
+
+    /// Take the buffered blocks as a blob once they reach `max_len` bytes.
+    pub fn take_if_full(&mut self, opts: &MakePatchOptions, max_len: usize, seekable: bool) -> Option<PendingBlob> {
+        if self.data.len() < max_len {
+            return None;
+        }
+        self.take(opts, None, seekable)
+    }
+}
+
+/// Compresses pending blobs on a worker pool and writes them in order.
+pub(crate) struct BlobPipeline<'a> {
+    pool: &'a rayon::ThreadPool,
+    opts: &'a MakePatchOptions,
+    dictionary: Option<compress::Dictionary>,
+    training: Option<Training>,
+    pending: Vec<PendingBlob>,
+    pending_bytes: usize,
+    max_bytes: usize,
+}
+
+/// ADD blocks sampled for the dictionary so far.
+struct Training {
+    samples: Vec<Vec<u8>>,
+    sampled: usize,
+    budget: usize, // 100 × dictionary size
+}
+
+impl<'a> BlobPipeline<'a> {
+    pub fn new(pool: &'a rayon::ThreadPool, opts: &'a MakePatchOptions) -> Self {
+        let per_thread = opts.solid_group_size.max(4 << 20);
+        let uses_zstd = std::iter::once(opts.codec)
+            .chain(opts.codec_by_extension.iter().map(|&(_, c)| c))
+            .any(|c| c.id() == codec::ZSTD_ID);
+        let training = (opts.dictionary_size > 0 && opts.zstd_level >= 0 && uses_zstd).then(|| Training {
+            samples: Vec::new(),
+            sampled: 0,
+            budget: opts.dictionary_size.saturating_mul(100),
+        });
+        Self {
+            pool,
+            opts,
+            dictionary: None,
+            training,
+            pending: Vec::new(),
+            pending_bytes: 0,
+            max_bytes: per_thread.saturating_mul(pool.current_num_threads()),
+        }
+    }
+
+    /// Offer an ADD block as a dictionary training sample; once enough are
+    /// collected the dictionary is trained and written.
+    pub fn sample<W: Write>(&mut self, block: &[u8], writer: &mut PatchWriter<W>, manifest: &mut Manifest) -> Result<()> {
+        if let Some(training) = &mut self.training {
+            training.samples.push(block.to_vec());
+            training.sampled += block.len();
+            if training.sampled >= training.budget {
+                self.finish_training(writer, manifest)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Train the dictionary from the samples so far and write it as a blob.
+    /// Without enough data to train on, no dictionary is used.
+    fn finish_training<W: Write>(&mut self, writer: &mut PatchWriter<W>, manifest: &mut Manifest) -> Result<()> {
+        if let Some(training) = self.training.take() {
+            self.dictionary =
+                compress::Dictionary::train(&training.samples, self.opts.dictionary_size, self.opts.zstd_level);
+            if let Some(dictionary) = &self.dictionary {
+                manifest.dictionary_offset = Some(writer.append_add_blob(&dictionary.raw, &codec::Store, 0)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue a blob, compressing and writing the batch once it is full. A
+    /// full batch also ends dictionary training with the samples so far.
+    pub fn push<W: Write>(&mut self, blob: PendingBlob, writer: &mut PatchWriter<W>, manifest: &mut Manifest) -> Result<()> {
+        self.pending_bytes += blob.data.len() + blob.prefix.as_ref().map_or(0, Vec::len);
+        self.pending.push(blob);
+        if self.pending_bytes >= self.max_bytes {
+            self.flush(writer, manifest)?;
+        }
+        Ok(())
+    }
+
+    /// Compress and write every queued blob.
+    pub fn flush<W: Write>(&mut self, writer: &mut PatchWriter<W>, manifest: &mut Manifest) -> Result<()> {
+        self.finish_training(writer, manifest)?;
+        let pending = std::mem::take(&mut self.pending);
+        self.pending_bytes = 0;
+
+        let (opts, dictionary) = (self.opts, self.dictionary.as_ref());
+        let payloads: Vec<Result<Option<Vec<u8>>>> = self
+            .pool
+            .install(|| pending.par_iter().map(|blob| blob.compress(opts, dictionary)).collect());
+
+        for (blob, payload) in pending.iter().zip(payloads) {
+            blob.write(payload?, writer, manifest, opts)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compress `data` with `compress`, or return `None` when
+/// `opts.skip_incompressible` finds it not worth it and the data should be
+/// stored raw. The entropy pre-check is only meaningful for `single_block`
+/// blobs: byte entropy cannot see repeats across the blocks of a group.
+fn compress_unless_incompressible(
+    codec: &dyn Codec,
+    data: &[u8],
+    opts: &MakePatchOptions,
+    single_block: bool,
+    compress: impl FnOnce(&[u8]) -> Result<Vec<u8>>,
+) -> Result<Option<Vec<u8>>> {
+    let check = match &opts.skip_incompressible {
+        Some(check) if codec.id() != codec::STORE_ID && !data.is_empty() => check,
+        _ => return compress(data).map(Some),
+    };
+    if single_block && compress::byte_entropy(data) > check.max_entropy {
+        return Ok(None);
+    }
+
+    let payload = compress(data)?;
+    let gain = 1.0 - payload.len() as f64 / data.len() as f64;
+    Ok((gain >= check.min_gain).then_some(payload))
+}
+
+/// Compress one blob or seekable frame with `codec`, against the patch
+/// dictionary when the codec is zstd.
+fn compress_block(
+    codec: &dyn Codec,
+    data: &[u8],
+    level: i32,
+    dictionary: Option<&compress::Dictionary>,
+) -> Result<Vec<u8>> {
+    if codec.id() == codec::ZSTD_ID {
+        compress::compress_with(data, level, dictionary)
+    } else {
+        codec.compress(data, level)
+    }
+}
+
+/// Where an ADD block's data first appeared: (entry index, op index, offset
+/// into the op's data).
+type AddLocation = (usize, usize, u64);
+
+/// ADD blocks by SHA-256, so repeated new content is stored only once.
+#[derive(Default)]
+pub(crate) struct AddIndex {
+    blocks: HashMap<String, AddLocation>,
+    refs: Vec<AddRef>,
+}
+
+/// An ADD op reading data stored for an earlier op.
+struct AddRef {
+    at: (usize, usize), // (entry index, op index) of the referencing op
+    origin: AddLocation,
+}
+
+impl AddIndex {
+    /// The first ADD block with this hash, if any.
+    pub fn find(&self, sha256: &str) -> Option<AddLocation> {
+        self.blocks.get(sha256).copied()
+    }
+
+    /// Record a block stored at `location`, unless the hash is already known.
+    pub fn insert(&mut self, sha256: String, location: AddLocation) {
+        self.blocks.entry(sha256).or_insert(location);
+    }
+
+    /// Append `len` bytes read from `origin` to `entry_idx`'s ops, extending
+    /// the previous op when it references the bytes just before.
+    pub fn push_ref(&mut self, manifest: &mut Manifest, entry_idx: usize, origin: AddLocation, len: u64) {
+        let ops = &mut manifest.entries[entry_idx].ops;
+        let last_at = ops.len().checked_sub(1).map(|op_idx| (entry_idx, op_idx));
+        if let (Some(PatchOp::Add { data_length, .. }), Some(last)) = (ops.last_mut(), self.refs.last()) {
+            let (e, o, offset) = last.origin;
+            if Some(last.at) == last_at && (e, o, offset + *data_length) == origin {
+                *data_length += len;
+                return;
+            }
+        }
+        self.refs.push(AddRef {
+            at: (entry_idx, ops.len()),
+            origin,
+        });
+        ops.push(PatchOp::Add {
+            data_offset: 0, // Filled in by `resolve`
+            data_length: len,
+            compressed: false,
+            compression: None,
+            zstd_level: None,
+            blob_offset: 0,
+            base: None,
+            xor: None,
+        });
+    }
+
+    /// Point every referencing op at its origin's blob. Call once all blobs
+    /// are written.
+    pub fn resolve(&self, manifest: &mut Manifest) {
+        for r in &self.refs {
+            let (entry_idx, op_idx, offset) = r.origin;
+            let origin = manifest.entries[entry_idx].ops[op_idx].clone();
+            let (at_entry, at_op) = r.at;
+            if let (
+                PatchOp::Add {
+                    data_offset,
+                    compressed,
+                    compression,
+                    zstd_level,
+                    blob_offset,
+                    base,
+                    ..
+                },
+                PatchOp::Add { data_length, .. },
+            ) = (origin, &manifest.entries[at_entry].ops[at_op])
+            {
+                manifest.entries[at_entry].ops[at_op] = PatchOp::Add {
+                    data_offset,
+                    data_length: *data_length,
+                    compressed,
+                    compression,
+                    zstd_level,
+                    blob_offset: blob_offset + offset,
+                    base,
+                    xor: None, // XOR deltas are never indexed
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ManifestEntry;
+
+    /// `len` bytes of xorshift noise.
+    fn noise(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    fn group(data: Vec<u8>) -> AddGroup {
+        AddGroup {
+            data,
+            members: vec![(0, 0)],
+            codec: None,
+        }
+    }
+
+    #[test]
+    fn high_entropy_runs_are_still_trial_compressed() {
+        let opts = MakePatchOptions::default();
+        let block = noise(opts.block_size, 1);
+        let run = block.repeat(4);
+        let zstd = |data: &[u8]| compress::compress(data, opts.zstd_level);
+        assert!(compress::byte_entropy(&run) > 7.9);
+
+        // A lone block is judged by its entropy alone
+        assert!(compress_unless_incompressible(&codec::Zstd, &block, &opts, true, zstd).unwrap().is_none());
+        // A run of blocks can repeat itself, which entropy cannot see
+        let payload = compress_unless_incompressible(&codec::Zstd, &run, &opts, false, zstd).unwrap();
+        assert!(payload.is_some_and(|payload| payload.len() < run.len() / 2));
+    }
+
+    #[test]
+    fn take_sizes_the_blob_before_draining() {
+        let opts = MakePatchOptions::default();
+        let mut run = group(noise(2 * opts.block_size, 2));
+        let blob = run.take(&opts, None, false).unwrap();
+        assert!(!blob.single_block);
+        assert_eq!(blob.data.len(), 2 * opts.block_size);
+        assert!(run.data.is_empty() && run.members.is_empty());
+        assert!(run.take(&opts, None, false).is_none());
+
+        let blob = group(noise(opts.block_size, 3)).take(&opts, None, false).unwrap();
+        assert!(blob.single_block);
+    }
+
+    #[test]
+    fn take_if_full_waits_for_max_len() {
+        let opts = MakePatchOptions::default();
+        let mut run = group(noise(opts.block_size, 4));
+        assert!(run.take_if_full(&opts, 2 * opts.block_size, false).is_none());
+        run.data.extend(noise(opts.block_size, 5));
+        assert!(run.take_if_full(&opts, 2 * opts.block_size, false).is_some());
+    }
+
+    #[test]
+    fn full_batch_ends_training() {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let opts = MakePatchOptions {
+            dictionary_size: 1 << 20, // Sample budget far above one batch
+            ..Default::default()
+        };
+        let mut pipeline = BlobPipeline::new(&pool, &opts);
+        let mut writer = PatchWriter::new(Vec::new()).unwrap();
+        let mut manifest = Manifest::default();
+        manifest.entries.push(ManifestEntry {
+            path: "a".to_string(),
+            entry_type: "file".to_string(),
+            mode: 0o644,
+            mtime: 0,
+            sha256: None,
+            renamed_from: None,
+            ops: vec![PatchOp::Add {
+                data_offset: 0,
+                data_length: 0,
+                compressed: true,
+                compression: None,
+                zstd_level: None,
+                blob_offset: 0,
+                base: None,
+                xor: None,
+            }],
+        });
+
+        let mut seed = 6;
+        while pipeline.pending_bytes < pipeline.max_bytes / 2 {
+            let blob = group(noise(1 << 20, seed)).take(&opts, None, false).unwrap();
+            pipeline.push(blob, &mut writer, &mut manifest).unwrap();
+            seed += 1;
+        }
+        assert!(pipeline.training.is_some() && !pipeline.pending.is_empty());
+
+        // A patch-from prefix counts towards the batch too
+        let blob = group(noise(opts.block_size, seed)).take(&opts, Some(vec![0; pipeline.max_bytes]), false);
+        pipeline.push(blob.unwrap(), &mut writer, &mut manifest).unwrap();
+        assert!(pipeline.training.is_none());
+        assert!(pipeline.pending.is_empty() && pipeline.pending_bytes == 0);
+    }
+}
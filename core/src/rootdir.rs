@@ -0,0 +1,386 @@
+//! Filesystem access confined to a target root.
+//!
+//! All opens and creates during apply go through a [`RootDir`] handle. On
+//! Unix every path is resolved relative to a directory file descriptor,
+//! one component at a time with `O_NOFOLLOW`, so a symlink planted inside
+//! the target (e.g. `data -> /etc`) can never redirect a write outside the
+//! root. On Linux directory lookups first try `openat2(RESOLVE_BENEATH |
+//! RESOLVE_NO_SYMLINKS)` and fall back to the component walk on older
+//! kernels.
+
+use crate::types::{PatchError, Result};
+use std::path::{Component, Path};
+
+pub use imp::RootDir;
+
+/// Split a relative path into its parent directory and final name, refusing
+/// anything that is not a plain component.
+fn split_parent(rel: &Path) -> Result<(&Path, &std::ffi::OsStr)> {
+    check_components(rel)?;
+    match (rel.parent(), rel.file_name()) {
+        (Some(parent), Some(name)) => Ok((parent, name)),
+        _ => Err(PatchError::Format(format!("Invalid path {:?}", rel))),
+    }
+}
+
+fn check_components(rel: &Path) -> Result<()> {
+    if rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        Ok(())
+    } else {
+        Err(PatchError::Format(format!("Path {:?} escapes the target root", rel)))
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::{check_components, split_parent};
+    use crate::types::{PatchError, Result};
+    use std::ffi::{CString, OsStr};
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    /// Handle to a target root directory.
+    #[derive(Debug)]
+    pub struct RootDir {
+        fd: OwnedFd,
+        beneath: bool, // Try `openat2` before walking components
+    }
+
+    fn c_name(name: &OsStr) -> Result<CString> {
+        CString::new(name.as_bytes())
+            .map_err(|_| PatchError::Format(format!("Path {:?} contains a NUL byte", name)))
+    }
+
+    fn os_error(what: &str, path: &Path) -> PatchError {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ELOOP) || err.raw_os_error() == Some(libc::EXDEV) {
+            return PatchError::Format(format!(
+                "Refusing to follow symlink while {} {:?}",
+                what, path
+            ));
+        }
+        PatchError::Io(io::Error::new(
+            err.kind(),
+            format!("{} {:?}: {}", what, path, err),
+        ))
+    }
+
+    /// Whether `name` in `dir` is a symlink; false if it does not exist.
+    fn is_symlink(dir: RawFd, name: &CString) -> bool {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        let rc = unsafe { libc::fstatat(dir, name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW) };
+        rc == 0 && stat.st_mode & libc::S_IFMT == libc::S_IFLNK
+    }
+
+    fn openat(dir: RawFd, name: &OsStr, flags: libc::c_int, rel: &Path, what: &str) -> Result<OwnedFd> {
+        let c = c_name(name)?;
+        let fd = unsafe { libc::openat(dir, c.as_ptr(), flags | libc::O_CLOEXEC | libc::O_NOFOLLOW, 0o666) };
+        if fd < 0 {
+            let err = os_error(what, rel);
+            // With O_DIRECTORY a link fails as ENOTDIR rather than ELOOP
+            if matches!(err, PatchError::Io(_)) && is_symlink(dir, &c) {
+                return Err(PatchError::Format(format!("Refusing to follow symlink while {} {:?}", what, rel)));
+            }
+            return Err(err);
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    impl RootDir {
+        /// Open `root` as the directory all later operations are confined to.
+        pub fn open(root: &Path) -> Result<Self> {
+            let c = c_name(root.as_os_str())?;
+            let fd = unsafe {
+                libc::open(c.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC)
+            };
+            if fd < 0 {
+                return Err(os_error("opening", root));
+            }
+            Ok(Self {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                beneath: true,
+            })
+        }
+
+        /// Always walk components, as on kernels without `openat2`.
+        #[cfg(test)]
+        pub fn without_openat2(self) -> Self {
+            Self { beneath: false, ..self }
+        }
+
+        /// Create `rel` and any missing parents below the root.
+        pub fn create_dir_all(&self, rel: &Path) -> Result<()> {
+            check_components(rel)?;
+            self.open_dir(rel, true).map(drop)
+        }
+
+        /// Open an existing regular file below the root for reading.
+        pub fn open_file(&self, rel: &Path) -> Result<File> {
+            let (parent, name) = split_parent(rel)?;
+            let dir = self.open_dir(parent, false)?;
+            let fd = openat(dir.as_raw_fd(), name, libc::O_RDONLY, rel, "opening")?;
+            Ok(File::from(fd))
+        }
+
+        /// Create (or truncate) a file below the root for writing, creating
+        /// missing parent directories.
+        pub fn create_file(&self, rel: &Path) -> Result<File> {
+            let (parent, name) = split_parent(rel)?;
+            let dir = self.open_dir(parent, true)?;
+            let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC;
+            let fd = openat(dir.as_raw_fd(), name, flags, rel, "creating")?;
+            Ok(File::from(fd))
+        }
+
+        /// Rename `from` to `to`, both relative to the root.
+        pub fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+            let (from_parent, from_name) = split_parent(from)?;
+            let (to_parent, to_name) = split_parent(to)?;
+            let from_dir = self.open_dir(from_parent, false)?;
+            let to_dir = self.open_dir(to_parent, false)?;
+            let (from_c, to_c) = (c_name(from_name)?, c_name(to_name)?);
+            // Links are never moved or replaced, even though that would not
+            // follow them
+            for (dir, name, rel) in [(&from_dir, &from_c, from), (&to_dir, &to_c, to)] {
+                if is_symlink(dir.as_raw_fd(), name) {
+                    return Err(PatchError::Format(format!("Refusing to rename symlink {:?}", rel)));
+                }
+            }
+            let rc = unsafe {
+                libc::renameat(
+                    from_dir.as_raw_fd(),
+                    from_c.as_ptr(),
+                    to_dir.as_raw_fd(),
+                    to_c.as_ptr(),
+                )
+            };
+            if rc != 0 {
+                return Err(os_error("renaming", to));
+            }
+            Ok(())
+        }
+
+        /// Open directory `rel` below the root, optionally creating it.
+        fn open_dir(&self, rel: &Path, create: bool) -> Result<OwnedFd> {
+            if rel.as_os_str().is_empty() {
+                return self.fd.try_clone().map_err(PatchError::Io);
+            }
+            if !create && self.beneath {
+                if let Some(fd) = self.open_dir_beneath(rel)? {
+                    return Ok(fd);
+                }
+            }
+
+            let mut current = self.fd.try_clone()?;
+            for component in rel.components() {
+                let name = component.as_os_str();
+                if create {
+                    let c = c_name(name)?;
+                    let rc = unsafe { libc::mkdirat(current.as_raw_fd(), c.as_ptr(), 0o777) };
+                    if rc != 0 && io::Error::last_os_error().raw_os_error() != Some(libc::EEXIST) {
+                        return Err(os_error("creating directory", rel));
+                    }
+                }
+                current = openat(
+                    current.as_raw_fd(),
+                    name,
+                    libc::O_RDONLY | libc::O_DIRECTORY,
+                    rel,
+                    "opening directory",
+                )?;
+            }
+            Ok(current)
+        }
+
+        /// Resolve a directory in one `openat2` call. Returns `None` when the
+        /// kernel does not support it so the caller can walk components.
+        #[cfg(target_os = "linux")]
+        fn open_dir_beneath(&self, rel: &Path) -> Result<Option<OwnedFd>> {
+            let c = c_name(rel.as_os_str())?;
+            let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+            how.flags = (libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC) as u64;
+            how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_SYMLINKS | libc::RESOLVE_NO_MAGICLINKS;
+            let fd = unsafe {
+                libc::syscall(
+                    libc::SYS_openat2,
+                    self.fd.as_raw_fd(),
+                    c.as_ptr(),
+                    &how as *const libc::open_how,
+                    std::mem::size_of::<libc::open_how>(),
+                )
+            };
+            if fd < 0 {
+                return match io::Error::last_os_error().raw_os_error() {
+                    Some(libc::ENOSYS) | Some(libc::EPERM) => Ok(None),
+                    _ => Err(os_error("opening directory", rel)),
+                };
+            }
+            Ok(Some(unsafe { OwnedFd::from_raw_fd(fd as RawFd) }))
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        fn open_dir_beneath(&self, _rel: &Path) -> Result<Option<OwnedFd>> {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    //! Path-based fallback: checks each existing component with
+    //! `symlink_metadata` before use. This rejects links already present in
+    //! the tree but, unlike the Unix implementation, is not race-free.
+
+    use super::{check_components, split_parent};
+    use crate::types::{PatchError, Result};
+    use std::fs::{self, File};
+    use std::path::{Path, PathBuf};
+
+    /// Handle to a target root directory.
+    #[derive(Debug)]
+    pub struct RootDir {
+        root: PathBuf,
+    }
+
+    impl RootDir {
+        pub fn open(root: &Path) -> Result<Self> {
+            if !fs::metadata(root)?.is_dir() {
+                return Err(PatchError::Format(format!("{:?} is not a directory", root)));
+            }
+            Ok(Self {
+                root: root.to_path_buf(),
+            })
+        }
+
+        pub fn create_dir_all(&self, rel: &Path) -> Result<()> {
+            check_components(rel)?;
+            let mut current = self.root.clone();
+            for component in rel.components() {
+                current.push(component);
+                match fs::symlink_metadata(&current) {
+                    Ok(meta) if meta.file_type().is_symlink() => return Err(symlink_error(&current)),
+                    Ok(_) => {}
+                    Err(_) => fs::create_dir(&current)?,
+                }
+            }
+            Ok(())
+        }
+
+        pub fn open_file(&self, rel: &Path) -> Result<File> {
+            Ok(File::open(self.checked(rel)?)?)
+        }
+
+        pub fn create_file(&self, rel: &Path) -> Result<File> {
+            let (parent, _) = split_parent(rel)?;
+            self.create_dir_all(parent)?;
+            Ok(File::create(self.checked(rel)?)?)
+        }
+
+        pub fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+            fs::rename(self.checked(from)?, self.checked(to)?)?;
+            Ok(())
+        }
+
+        fn checked(&self, rel: &Path) -> Result<PathBuf> {
+            check_components(rel)?;
+            let mut current = self.root.clone();
+            for component in rel.components() {
+                current.push(component);
+                if let Ok(meta) = fs::symlink_metadata(&current) {
+                    if meta.file_type().is_symlink() {
+                        return Err(symlink_error(&current));
+                    }
+                }
+            }
+            Ok(current)
+        }
+    }
+
+    fn symlink_error(path: &Path) -> PatchError {
+        PatchError::Format(format!("Refusing to follow symlink {:?}", path))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    /// A target root and a directory outside it holding `secret`, with
+    /// `root/link_dir -> outside` and `root/link_file -> outside/secret`.
+    fn planted() -> (tempfile::TempDir, std::path::PathBuf) {
+        let tmp = tempfile::tempdir().unwrap();
+        let (root, outside) = (tmp.path().join("root"), tmp.path().join("outside"));
+        fs::create_dir_all(root.join("real")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("secret"), b"secret").unwrap();
+        fs::write(root.join("real/file"), b"data").unwrap();
+        symlink(&outside, root.join("link_dir")).unwrap();
+        symlink(outside.join("secret"), root.join("link_file")).unwrap();
+        (tmp, root)
+    }
+
+    /// Both lookup strategies: `openat2` where available and the walk.
+    fn roots(root: &Path) -> [RootDir; 2] {
+        [RootDir::open(root).unwrap(), RootDir::open(root).unwrap().without_openat2()]
+    }
+
+    fn refused<T: std::fmt::Debug>(result: Result<T>) -> bool {
+        matches!(result, Err(PatchError::Format(_)))
+    }
+
+    #[test]
+    fn opens_plain_paths() {
+        let (_tmp, root) = planted();
+        for dir in roots(&root) {
+            dir.open_file(Path::new("real/file")).unwrap();
+            dir.create_file(Path::new("new/dir/file")).unwrap();
+            dir.rename(Path::new("new/dir/file"), Path::new("real/moved")).unwrap();
+            dir.rename(Path::new("real/moved"), Path::new("new/dir/file")).unwrap();
+        }
+    }
+
+    #[test]
+    fn refuses_symlinked_directory_component() {
+        let (tmp, root) = planted();
+        for dir in roots(&root) {
+            assert!(refused(dir.open_file(Path::new("link_dir/secret"))));
+            assert!(refused(dir.create_file(Path::new("link_dir/planted"))));
+            assert!(refused(dir.create_dir_all(Path::new("link_dir/sub"))));
+            assert!(refused(dir.rename(Path::new("real/file"), Path::new("link_dir/stolen"))));
+            assert!(refused(dir.rename(Path::new("link_dir/secret"), Path::new("real/stolen"))));
+        }
+        assert!(!tmp.path().join("outside/planted").exists());
+        assert!(!tmp.path().join("outside/stolen").exists());
+        assert!(tmp.path().join("outside/secret").exists());
+    }
+
+    #[test]
+    fn refuses_symlinked_final_file() {
+        let (tmp, root) = planted();
+        for dir in roots(&root) {
+            assert!(refused(dir.open_file(Path::new("link_file"))));
+            assert!(refused(dir.create_file(Path::new("link_file"))));
+            assert!(refused(dir.rename(Path::new("real/file"), Path::new("link_file"))));
+            assert!(refused(dir.rename(Path::new("link_file"), Path::new("real/stolen"))));
+        }
+        assert_eq!(fs::read(tmp.path().join("outside/secret")).unwrap(), b"secret");
+        assert!(fs::symlink_metadata(root.join("link_file")).unwrap().file_type().is_symlink());
+    }
+
+    #[test]
+    fn refuses_copy_source_outside_root() {
+        // A COPY source is opened like this before its bytes are read
+        let (_tmp, root) = planted();
+        for dir in roots(&root) {
+            for src in ["link_file", "link_dir/secret"] {
+                let rel = crate::paths::decode_relative_path(src).unwrap();
+                assert!(refused(dir.open_file(&rel)), "{}", src);
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Scheduling for parallel apply.
+//!
+//! Files are rebuilt into temporary siblings concurrently. COPY ops and
+//! patch-from bases read the *old* contents of other target files, so a
+//! rebuilt file is only renamed into place once every entry that reads its
+//! path has finished. [`CommitTracker`] keeps those reader counts and
+//! [`ByteBudget`] bounds how much output is being written at once.
+//!
+//! Entries with COPY_OUTPUT ops reading other files' *new* contents run
+//! after the rest; the tracker tells them where those contents are.
+
+use crate::types::{PatchError, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+/// Decides when a rebuilt file may replace the original.
+pub(crate) struct CommitTracker {
+    state: Mutex<CommitState>,
+}
+
+struct CommitState {
+    readers_left: HashMap<PathBuf, usize>, // Target path -> entries still to read it
+    built: HashSet<PathBuf>,               // Rebuilt, waiting for readers
+    outputs: HashMap<PathBuf, bool>,       // Path read by COPY_OUTPUT -> rebuilt yet
+}
+
+/// Where the new contents of a rebuilt file are.
+pub(crate) enum Rebuilt {
+    Temporary, // Its temporary sibling, waiting for readers of the original
+    InPlace,
+}
+
+impl CommitTracker {
+    /// `reads` holds, per file entry, the other target paths it reads;
+    /// `outputs` the paths whose new contents are copied by other entries.
+    pub fn new<'a>(reads: impl IntoIterator<Item = &'a HashSet<PathBuf>>, outputs: &HashSet<PathBuf>) -> Self {
+        let mut readers_left = HashMap::new();
+        for paths in reads {
+            for path in paths {
+                *readers_left.entry(path.clone()).or_insert(0) += 1;
+            }
+        }
+        Self {
+            state: Mutex::new(CommitState {
+                readers_left,
+                built: HashSet::new(),
+                outputs: outputs.iter().map(|path| (path.clone(), false)).collect(),
+            }),
+        }
+    }
+
+    /// Where the new contents of `path`, one of the `outputs`, are, or
+    /// `None` if it has not been rebuilt.
+    pub fn rebuilt(&self, path: &Path) -> Result<Option<Rebuilt>> {
+        let state = self
+            .state
+            .lock()
+            .map_err(|_| PatchError::Format("Apply worker panicked".to_string()))?;
+        Ok(match state.outputs.get(path) {
+            Some(true) if state.built.contains(path) => Some(Rebuilt::Temporary),
+            Some(true) => Some(Rebuilt::InPlace),
+            _ => None,
+        })
+    }
+
+    /// Record that the entry writing `written` has been rebuilt after
+    /// reading `read`, and call `commit` for every file that can now be
+    /// renamed into place.
+    pub fn finish(
+        &self,
+        written: &Path,
+        read: &HashSet<PathBuf>,
+        mut commit: impl FnMut(&Path) -> Result<()>,
+    ) -> Result<()> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| PatchError::Format("Apply worker panicked".to_string()))?;
+
+        if let Some(rebuilt) = state.outputs.get_mut(written) {
+            *rebuilt = true;
+        }
+        for path in read {
+            let left = state.readers_left.get_mut(path).expect("reader counted in new");
+            *left -= 1;
+            if *left == 0 && state.built.remove(path) {
+                commit(path)?;
+            }
+        }
+
+        if state.readers_left.get(written).is_some_and(|&left| left > 0) {
+            state.built.insert(written.to_path_buf());
+        } else {
+            commit(written)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counting semaphore over bytes of output being written concurrently.
+pub(crate) struct ByteBudget {
+    total: u64,
+    available: Mutex<u64>,
+    freed: Condvar,
+}
+
+impl ByteBudget {
+    pub fn new(total: u64) -> Self {
+        let total = total.max(1);
+        Self {
+            total,
+            available: Mutex::new(total),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Wait until `bytes` (capped at the whole budget) are available.
+    pub fn acquire(&self, bytes: u64) -> Result<BudgetGuard<'_>> {
+        let bytes = bytes.min(self.total);
+        let poisoned = |_| PatchError::Format("Apply worker panicked".to_string());
+        let mut available = self.available.lock().map_err(poisoned)?;
+        while *available < bytes {
+            available = self.freed.wait(available).map_err(poisoned)?;
+        }
+        *available -= bytes;
+        Ok(BudgetGuard { budget: self, bytes })
+    }
+}
+
+/// Returns its bytes to the [`ByteBudget`] when dropped.
+pub(crate) struct BudgetGuard<'a> {
+    budget: &'a ByteBudget,
+    bytes: u64,
+}
+
+impl Drop for BudgetGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut available) = self.budget.available.lock() {
+            *available += self.bytes;
+        }
+        self.budget.freed.notify_all();
+    }
+}
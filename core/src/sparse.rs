@@ -0,0 +1,79 @@
+//! Hole detection for sparse files.
+//!
+//! Files are scanned front to back; [`Holes`] asks the filesystem with
+//! `SEEK_HOLE`/`SEEK_DATA` where the next hole starts, so reading and
+//! hashing skip holes at the cost of a couple of `lseek` calls per extent.
+//! Without OS support every file reads as all data.
+
+use std::fs::File;
+
+/// Tracks the data and holes of one file as it is read.
+#[derive(Default)]
+pub(crate) struct Holes {
+    data_end: u64, // Known data, without holes, up to here
+}
+
+impl Holes {
+    /// Length of the hole starting at `pos`, 0 if `pos` holds data. The
+    /// file position is left unchanged.
+    pub fn at(&mut self, file: &File, pos: u64) -> u64 {
+        if pos < self.data_end {
+            return 0;
+        }
+        match find_hole(file, pos) {
+            Some(Extent::Data(end)) => {
+                self.data_end = end;
+                0
+            }
+            Some(Extent::Hole(len)) => len,
+            None => {
+                self.data_end = u64::MAX;
+                0
+            }
+        }
+    }
+}
+
+enum Extent {
+    Data(u64), // Data from the position until this offset
+    Hole(u64), // A hole of this many bytes at the position
+}
+
+#[cfg(target_os = "linux")]
+fn find_hole(file: &File, pos: u64) -> Option<Extent> {
+    use std::os::fd::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let offset = libc::off_t::try_from(pos).ok()?;
+    let restore = unsafe { libc::lseek(fd, 0, libc::SEEK_CUR) };
+    if restore < 0 {
+        return None;
+    }
+
+    let hole = unsafe { libc::lseek(fd, offset, libc::SEEK_HOLE) };
+    let extent = if hole < 0 {
+        None
+    } else if hole > offset {
+        Some(Extent::Data(hole as u64))
+    } else {
+        // In a hole: it ends at the next data, or at the end of the file
+        let data = unsafe { libc::lseek(fd, offset, libc::SEEK_DATA) };
+        if data > offset {
+            Some(Extent::Hole((data - offset) as u64))
+        } else if data < 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) {
+            file.metadata().ok().map(|meta| Extent::Hole(meta.len().saturating_sub(pos)))
+        } else {
+            None
+        }
+    };
+
+    if unsafe { libc::lseek(fd, restore, libc::SEEK_SET) } < 0 {
+        return None;
+    }
+    extent
+}
+
+#[cfg(not(target_os = "linux"))]
+fn find_hole(_file: &File, _pos: u64) -> Option<Extent> {
+    None
+}
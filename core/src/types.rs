@@ -1,8 +1,8 @@
 //! Shared types for PatchForge core library.
 
+use crate::codec::{self, Codec};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 /// Error type for core operations.
 #[derive(Debug, thiserror::Error)]
@@ -29,12 +29,88 @@ pub enum PatchError {
 /// Result alias for core operations.
 pub type Result<T> = std::result::Result<T, PatchError>;
 
+/// How ADD payloads are grouped into compressed blobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionMode {
+    /// One blob per run of consecutive new blocks, up to 4 MiB, encoded
+    /// with the selected codec.
+    #[default]
+    Blocks,
+    /// ADD blocks are concatenated into groups compressed as one frame.
+    Solid,
+    /// Like `Solid`, but each group is split into independent frames with
+    /// a frame index so ranges can be decoded without the whole group.
+    Seekable,
+}
+
+impl std::str::FromStr for CompressionMode {
+    type Err = PatchError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "blocks" => Ok(Self::Blocks),
+            "solid" => Ok(Self::Solid),
+            "seekable" => Ok(Self::Seekable),
+            other => Err(PatchError::Unsupported(format!(
+                "Unknown compression mode {:?}",
+                other
+            ))),
+        }
+    }
+}
+
 /// Options for creating a patch.
 #[derive(Debug, Clone)]
 pub struct MakePatchOptions {
     pub block_size: usize,      // Fixed block size (4096)
-    pub zstd_level: i32,        // -1 for no compression, 0-22 for levels
+    pub zstd_level: i32,        // -1 for no compression, else the codec's level
     pub verify_checksums: bool, // Validate blocks during creation
+    pub compression_mode: CompressionMode,
+    pub solid_group_size: usize,   // Raw bytes per solid/seekable group
+    pub seekable_frame_size: usize, // Raw bytes per frame in seekable groups
+    pub patch_from: bool,          // Compress ADD data against the old file
+    pub patch_from_max_size: u64,  // Largest old file used as a reference
+    pub dictionary_size: usize,    // Trained zstd dictionary size, 0 to disable
+    pub codec: &'static dyn Codec, // Codec for ADD data
+    pub codec_by_extension: Vec<(String, &'static dyn Codec)>, // Per-file overrides
+    pub skip_incompressible: Option<IncompressibleCheck>, // None to always compress
+    pub threads: usize,            // Worker threads for hashing and compression, 0 = all cores
+    pub dedupe: bool,              // Store repeated ADD blocks once; fallback when output_copies is off
+    pub output_copies: bool,       // COPY_OUTPUT repeated new blocks from the rebuilt files
+    pub similar_blocks: bool,      // Store new blocks as XOR deltas against similar source blocks
+    pub detect_renames: bool,      // Pair new files with the most similar source file
+}
+
+/// Heuristic for storing ADD data raw when compressing it does not pay off.
+#[derive(Debug, Clone, Copy)]
+pub struct IncompressibleCheck {
+    pub max_entropy: f64, // Single blocks above this many bits/byte skip compression untried
+    pub min_gain: f64,    // Store raw when compression saves less than this fraction
+}
+
+impl Default for IncompressibleCheck {
+    fn default() -> Self {
+        Self {
+            max_entropy: 7.9,
+            min_gain: 0.02,
+        }
+    }
+}
+
+impl MakePatchOptions {
+    /// Codec for a destination file: the first extension override that
+    /// matches `path`, else `codec`. Store when compression is disabled.
+    pub fn codec_for(&self, path: &std::path::Path) -> &'static dyn Codec {
+        if self.zstd_level < 0 {
+            return &codec::Store;
+        }
+        let ext = path.extension().and_then(|e| e.to_str());
+        self.codec_by_extension
+            .iter()
+            .find(|(e, _)| Some(e.as_str()) == ext)
+            .map_or(self.codec, |&(_, c)| c)
+    }
 }
 
 impl Default for MakePatchOptions {
@@ -43,6 +119,44 @@ impl Default for MakePatchOptions {
             block_size: 4096,
             zstd_level: 3,
             verify_checksums: true,
+            compression_mode: CompressionMode::Blocks,
+            solid_group_size: 16 << 20,
+            seekable_frame_size: 256 << 10,
+            patch_from: false,
+            patch_from_max_size: 128 << 20,
+            dictionary_size: 0,
+            codec: &codec::Zstd,
+            codec_by_extension: Vec::new(),
+            skip_incompressible: Some(IncompressibleCheck::default()),
+            threads: 0,
+            dedupe: true,
+            output_copies: true,
+            similar_blocks: true,
+            detect_renames: true,
+        }
+    }
+}
+
+/// Resource limits enforced while reading an untrusted patch.
+///
+/// Every violation is reported as `PatchError::Format`.
+#[derive(Debug, Clone)]
+pub struct PatchLimits {
+    pub max_manifest_len: u64,   // Manifest bytes declared in the header
+    pub max_entries: usize,      // Manifest entries
+    pub max_blob_len: u64,       // Decompressed size of a single ADD blob
+    pub max_total_output: u64,   // Bytes written across all files
+    pub max_path_len: usize,     // Length of an encoded manifest path
+}
+
+impl Default for PatchLimits {
+    fn default() -> Self {
+        Self {
+            max_manifest_len: 512 << 20,
+            max_entries: 10_000_000,
+            max_blob_len: 256 << 20,
+            max_total_output: 1 << 40,
+            max_path_len: 4096,
         }
     }
 }
@@ -50,15 +164,25 @@ impl Default for MakePatchOptions {
 /// Options for applying a patch.
 #[derive(Debug, Clone)]
 pub struct ApplyPatchOptions {
+    pub block_size: usize, // Block size the patch was made with (4096)
     pub verify_checksums: bool,
     pub atomic: bool, // Use temp files and atomic renames
+    pub limits: PatchLimits,
+    pub threads: usize,          // Files rebuilt concurrently (atomic mode), 0 = all cores
+    pub max_inflight_bytes: u64, // Output bytes of the files being rebuilt at once
+    pub fast_copy: bool,         // Clone or kernel-copy COPY runs where the OS allows
 }
 
 impl Default for ApplyPatchOptions {
     fn default() -> Self {
         Self {
+            block_size: 4096,
             verify_checksums: true,
             atomic: true,
+            limits: PatchLimits::default(),
+            threads: 0,
+            max_inflight_bytes: 1 << 30,
+            fast_copy: true,
         }
     }
 }
@@ -80,9 +204,44 @@ pub enum PatchOp {
         data_offset: u64,   // Offset in patch Data section
         data_length: u64,   // Length of payload
         compressed: bool,   // Is payload compressed?
-        compression: Option<String>, // Compression type ("zstd")
+        compression: Option<String>, // Codec name ("zstd", "lz4", "xz", "brotli")
         zstd_level: Option<i32>,     // Compression level if compressed
+        #[serde(default)]
+        blob_offset: u64,   // Offset into the decoded blob (solid/seekable groups)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        base: Option<String>, // Source file used as zstd reference prefix
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        xor: Option<XorSource>, // Source blocks the data is XORed with
+    },
+    /// Copy bytes already written to the new version of a file.
+    #[serde(rename = "COPY_OUTPUT")]
+    CopyOutput {
+        path: String, // Earlier rebuilt file, or this entry's own path
+        offset: u64,  // Byte offset in that file's new contents
+        len: u64,     // Bytes to copy
     },
+    /// A run of zero bytes, left as a hole where the filesystem allows.
+    #[serde(rename = "ZERO")]
+    Zero {
+        len: u64, // Bytes of zeros
+    },
+    /// A short pattern repeated over a run of bytes.
+    #[serde(rename = "FILL")]
+    Fill {
+        pattern: Vec<u8>, // 1 to MAX_FILL_PATTERN bytes, starting the run
+        len: u64,         // Bytes of output; the last repeat may be cut short
+    },
+}
+
+/// Longest pattern a FILL op may carry.
+pub const MAX_FILL_PATTERN: usize = 64;
+
+/// Source bytes an ADD op's data is a delta against: the output is the
+/// data XORed with `data_length` bytes of `src` from `block_index` on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct XorSource {
+    pub src: String,      // Relative path in src
+    pub block_index: u64, // First block (offset = block_index * 4096)
 }
 
 /// File or directory entry in the patch manifest.
@@ -96,6 +255,8 @@ pub struct ManifestEntry {
     pub mtime: u64,                // Modification time (optional)
     #[serde(default)]
     pub sha256: Option<String>,    // Optional checksum
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renamed_from: Option<String>, // Source file paired with it, when at another path
     #[serde(default)]
     pub ops: Vec<PatchOp>,         // Operations to create this file
 }
@@ -104,6 +265,10 @@ pub struct ManifestEntry {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Manifest {
     pub version: u32,
+    #[serde(default)]
+    pub compression_mode: CompressionMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary_offset: Option<u64>, // Blob holding the trained zstd dictionary
     pub entries: Vec<ManifestEntry>,
 }
 
@@ -111,6 +276,8 @@ impl Manifest {
     pub fn new() -> Self {
         Self {
             version: 1,
+            compression_mode: CompressionMode::Blocks,
+            dictionary_offset: None,
             entries: Vec::new(),
         }
     }
@@ -124,6 +291,12 @@ impl Manifest {
     }
 }
 
+impl Default for Manifest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// In-memory patch representation.
 #[derive(Debug)]
 pub struct Patch {
@@ -140,6 +313,12 @@ impl Patch {
     }
 }
 
+impl Default for Patch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Block hash info for deduplication.
 #[derive(Debug, Clone)]
 pub struct BlockHash {
@@ -147,27 +326,43 @@ pub struct BlockHash {
     pub file_path: PathBuf,   // Which file it came from
     pub block_index: u64,     // Which block in that file
     pub len: usize,           // Actual length (may be < block_size for last block)
+    pub sketch: Option<Sketch>,   // Similarity sketch of a full block
 }
 
+/// Bottom-k min-hash of a block's 8-byte windows: similar blocks share
+/// most values.
+pub type Sketch = [u64; SKETCH_LEN];
+
+/// Values kept in a [`Sketch`].
+pub const SKETCH_LEN: usize = 4;
+
 /// Patch header (binary).
 #[derive(Debug)]
 pub struct PatchHeader {
-    pub magic: [u8; 8],      // "PATCHFG1\0" (8 bytes)
+    pub magic: [u8; 8],      // "PATCHFG1" (8 bytes)
     pub version: u32,        // Version number (big-endian)
     pub manifest_len: u64,   // Length of manifest (big-endian)
 }
 
 impl PatchHeader {
     pub fn new(manifest_len: u64) -> Self {
-        let mut magic = [0u8; 8];
-        magic[..7].copy_from_slice(b"PATCHFG1");
         Self {
-            magic,
+            magic: *b"PATCHFG1",
             version: 1,
             manifest_len,
         }
     }
 
+    /// Header for a streamed (version 2) patch; the manifest follows the
+    /// data section and is located through the [`PatchTrailer`].
+    pub fn streaming() -> Self {
+        Self {
+            magic: *b"PATCHFG1",
+            version: PATCH_VERSION_STREAMING,
+            manifest_len: 0,
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut buf = Vec::new();
         buf.extend_from_slice(&self.magic);
@@ -183,7 +378,7 @@ impl PatchHeader {
         let magic = [
             buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7],
         ];
-        if &magic[..7] != b"PATCHFG1" {
+        if &magic != b"PATCHFG1" {
             return Err(PatchError::Format("Invalid magic".to_string()));
         }
         let version = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
@@ -201,19 +396,84 @@ impl PatchHeader {
     }
 }
 
+/// Format version whose manifest is stored after the data section.
+pub const PATCH_VERSION_STREAMING: u32 = 2;
+
+/// Patch trailer (binary), the last 24 bytes of a version 2 patch.
+#[derive(Debug)]
+pub struct PatchTrailer {
+    pub data_len: u64,       // Length of data section (big-endian)
+    pub manifest_len: u64,   // Length of manifest (big-endian)
+    pub magic: [u8; 8],      // "PATCHEND" (8 bytes)
+}
+
+impl PatchTrailer {
+    pub const SIZE: usize = 24;
+
+    pub fn new(data_len: u64, manifest_len: u64) -> Self {
+        Self {
+            data_len,
+            manifest_len,
+            magic: *b"PATCHEND",
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.data_len.to_be_bytes());
+        buf.extend_from_slice(&self.manifest_len.to_be_bytes());
+        buf.extend_from_slice(&self.magic);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> crate::Result<Self> {
+        if buf.len() < Self::SIZE {
+            return Err(PatchError::Format("Trailer too short".to_string()));
+        }
+        let data_len = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+        let manifest_len = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+        let magic: [u8; 8] = buf[16..24].try_into().unwrap();
+        if &magic != b"PATCHEND" {
+            return Err(PatchError::Format("Invalid trailer magic".to_string()));
+        }
+        Ok(Self {
+            data_len,
+            manifest_len,
+            magic,
+        })
+    }
+}
+
 /// Blob header for ADD payloads in Data section.
 #[derive(Debug)]
 pub struct BlobHeader {
-    pub compressed: bool,   // 0 = none, 1 = zstd
-    pub zstd_level: u32,    // Compression level (big-endian u32)
+    pub codec: u8,          // Codec id (see `codec`); 2 = seekable zstd
+    pub seekable: bool,     // Payload is a frame index followed by frames
+    pub skipped: bool,      // Stored raw because compression did not pay off
+    pub level: u32,         // Compression level (big-endian u32)
     pub payload_len: u64,   // Payload length (big-endian u64)
 }
 
 impl BlobHeader {
+    /// Seekable flag for codecs other than zstd, which keeps its own id.
+    const SEEKABLE_BIT: u8 = 0x80;
+    const SEEKABLE_ZSTD: u8 = 2;
+    /// Set on store blobs whose data was judged incompressible.
+    const SKIPPED_BIT: u8 = 0x40;
+
+    pub fn compressed(&self) -> bool {
+        self.codec != codec::STORE_ID
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut buf = Vec::new();
-        buf.push(if self.compressed { 1 } else { 0 });
-        buf.extend_from_slice(&self.zstd_level.to_be_bytes());
+        let flag = match (self.codec, self.seekable) {
+            (codec::ZSTD_ID, true) => Self::SEEKABLE_ZSTD,
+            (id, true) => id | Self::SEEKABLE_BIT,
+            (id, false) => id,
+        };
+        buf.push(if self.skipped { flag | Self::SKIPPED_BIT } else { flag });
+        buf.extend_from_slice(&self.level.to_be_bytes());
         buf.extend_from_slice(&self.payload_len.to_be_bytes());
         buf
     }
@@ -222,15 +482,27 @@ impl BlobHeader {
         if buf.len() < 13 {
             return Err(PatchError::Format("Blob header too short".to_string()));
         }
-        let compressed = buf[0] != 0;
-        let zstd_level = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+        let skipped = buf[0] & Self::SKIPPED_BIT != 0;
+        let (codec, seekable) = match buf[0] & !Self::SKIPPED_BIT {
+            Self::SEEKABLE_ZSTD => (codec::ZSTD_ID, true),
+            flag => (flag & !Self::SEEKABLE_BIT, flag & Self::SEEKABLE_BIT != 0),
+        };
+        if codec == Self::SEEKABLE_ZSTD
+            || codec::by_id(codec).is_err()
+            || (skipped && (codec != codec::STORE_ID || seekable))
+        {
+            return Err(PatchError::Format(format!("Unknown blob flag {}", buf[0])));
+        }
+        let level = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
         let payload_len = u64::from_be_bytes([
             buf[5], buf[6], buf[7], buf[8], buf[9], buf[10], buf[11], buf[12],
         ]);
         Ok((
             Self {
-                compressed,
-                zstd_level,
+                codec,
+                seekable,
+                skipped,
+                level,
                 payload_len,
             },
             13,
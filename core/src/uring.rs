@@ -0,0 +1,215 @@
+//! io_uring batching for COPY runs (Linux, `io-uring` feature).
+//!
+//! Copies into one output file are queued and executed in batches: all
+//! reads of a batch are submitted at once, then all writes, each at an
+//! explicit offset. Short transfers are finished with ordinary positional
+//! I/O, so the output is the same as with the std::fs path. A ring whose
+//! submission fails is retired, and later outputs use std::fs instead.
+
+use crate::types::Result;
+use io_uring::{opcode, types::Fd, IoUring};
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+
+/// Copies in flight per batch.
+const DEPTH: usize = 32;
+/// Largest single read or write; longer copies are split.
+const CHUNK: u64 = 128 << 10;
+
+/// A ring and its transfer buffers, reused across output files.
+pub(crate) struct Ring {
+    ring: IoUring,
+    bufs: Vec<Vec<u8>>,
+    broken: bool, // A submission failed; entries may be left in the ring
+}
+
+impl Ring {
+    /// Set up a ring, or `None` when the kernel does not allow io_uring.
+    pub fn new() -> Option<Self> {
+        let ring = IoUring::new(DEPTH as u32).ok()?;
+        Some(Self {
+            ring,
+            bufs: Vec::new(),
+            broken: false,
+        })
+    }
+
+    /// Start queueing copies into `dst`, or `None` once the ring is retired.
+    pub fn batch<'a>(&'a mut self, dst: &'a File) -> Option<Batch<'a>> {
+        if self.broken {
+            return None;
+        }
+        Some(Batch {
+            ring: self,
+            dst,
+            queue: Vec::new(),
+        })
+    }
+}
+
+/// Copies queued for one output file. Dropping it discards unflushed copies.
+pub(crate) struct Batch<'a> {
+    ring: &'a mut Ring,
+    dst: &'a File,
+    queue: Vec<Job<'a>>,
+}
+
+struct Job<'a> {
+    src: &'a File,
+    src_offset: u64,
+    dst_offset: u64,
+    len: usize,
+}
+
+impl<'a> Batch<'a> {
+    /// Queue a copy of `len` bytes at `src_offset` to `dst_offset`.
+    pub fn copy(&mut self, src: &'a File, src_offset: u64, dst_offset: u64, len: u64) -> Result<()> {
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(CHUNK);
+            self.queue.push(Job {
+                src,
+                src_offset: src_offset + done,
+                dst_offset: dst_offset + done,
+                len: chunk as usize,
+            });
+            done += chunk;
+            if self.queue.len() == DEPTH {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute every queued copy.
+    pub fn flush(&mut self) -> Result<()> {
+        let jobs = std::mem::take(&mut self.queue);
+        if jobs.is_empty() {
+            return Ok(());
+        }
+        let bufs = &mut self.ring.bufs;
+        bufs.resize_with(bufs.len().max(jobs.len()), Vec::new);
+        for (job, buf) in jobs.iter().zip(bufs.iter_mut()) {
+            buf.resize(job.len, 0);
+        }
+
+        let read = self.run(&jobs, |job, buf| {
+            opcode::Read::new(Fd(job.src.as_raw_fd()), buf.as_mut_ptr(), job.len as u32)
+                .offset(job.src_offset)
+                .build()
+        })?;
+        for ((job, buf), n) in jobs.iter().zip(self.ring.bufs.iter_mut()).zip(read) {
+            job.src.read_exact_at(&mut buf[n..job.len], job.src_offset + n as u64)?;
+        }
+
+        let dst = Fd(self.dst.as_raw_fd());
+        let written = self.run(&jobs, |job, buf| {
+            opcode::Write::new(dst, buf.as_ptr(), job.len as u32)
+                .offset(job.dst_offset)
+                .build()
+        })?;
+        for ((job, buf), n) in jobs.iter().zip(&self.ring.bufs).zip(written) {
+            self.dst.write_all_at(&buf[n..job.len], job.dst_offset + n as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Submit one operation per job and wait for all of them, returning the
+    /// bytes each transferred.
+    fn run(
+        &mut self,
+        jobs: &[Job<'a>],
+        op: impl Fn(&Job<'a>, &mut Vec<u8>) -> io_uring::squeue::Entry,
+    ) -> Result<Vec<usize>> {
+        let Ring { ring, bufs, broken } = &mut *self.ring;
+        {
+            let mut sq = ring.submission();
+            for (i, (job, buf)) in jobs.iter().zip(bufs.iter_mut()).enumerate() {
+                let entry = op(job, buf).user_data(i as u64);
+                // The queue holds DEPTH entries and is empty between batches
+                // of a working ring
+                if unsafe { sq.push(&entry) }.is_err() {
+                    *broken = true;
+                    return Err(io::Error::other("io_uring submission queue is full").into());
+                }
+            }
+        }
+
+        let mut results: Vec<i32> = vec![0; jobs.len()];
+        let mut pending = jobs.len();
+        while pending > 0 {
+            let submitted = match ring.submit_and_wait(pending) {
+                // A submission that queues nothing would wait forever
+                Ok(0) if !ring.submission().is_empty() => {
+                    Err(io::Error::other("io_uring accepted no submissions"))
+                }
+                Ok(_) => Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = submitted {
+                // Operations may still be queued or in flight: never free
+                // their buffers, and never reuse the ring
+                std::mem::forget(std::mem::take(bufs));
+                *broken = true;
+                return Err(e.into());
+            }
+            for cqe in ring.completion() {
+                results[cqe.user_data() as usize] = cqe.result();
+                pending -= 1;
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|res| usize::try_from(res).map_err(|_| io::Error::from_raw_os_error(-res).into()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_match_std_fs() {
+        let Some(mut ring) = Ring::new() else {
+            return; // io_uring is not allowed here
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let mut seed = 1u64;
+        let data: Vec<u8> = (0..400_000)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect();
+        std::fs::write(dir.path().join("src"), &data).unwrap();
+        let src = File::open(dir.path().join("src")).unwrap();
+
+        // More short runs than one batch holds, then one split into chunks
+        let mut runs: Vec<(u64, u64)> = (0..100).map(|i| (i * 3001 % 390_000, 17 + i % 50)).collect();
+        runs.push((1, 3 * CHUNK - 5));
+        let (uring_out, std_out) = (dir.path().join("uring"), dir.path().join("std"));
+        let (dst, expected) = (File::create(&uring_out).unwrap(), File::create(&std_out).unwrap());
+        let mut batch = ring.batch(&dst).unwrap();
+        let mut pos = 0;
+        for &(offset, len) in &runs {
+            batch.copy(&src, offset, pos, len).unwrap();
+            let mut buf = vec![0; len as usize];
+            src.read_exact_at(&mut buf, offset).unwrap();
+            expected.write_all_at(&buf, pos).unwrap();
+            pos += len;
+        }
+        batch.flush().unwrap();
+        drop(batch);
+
+        assert!(std::fs::read(&uring_out).unwrap() == std::fs::read(&std_out).unwrap());
+        assert_eq!(std::fs::metadata(&uring_out).unwrap().len(), pos);
+        assert!(!ring.broken);
+    }
+}